@@ -3,35 +3,319 @@ extern crate stubby;
 #[macro_use]
 extern crate log;
 extern crate log4rs;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_yaml;
+
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use log::LogLevelFilter;
+use log4rs::append::Append;
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::config::{Appender, Config, Root};
+use log4rs::encode::EncoderConfig;
+use log4rs::file::{Deserialize, Deserializers, RawConfig};
 
-use stubby::Server;
+use stubby::{Server, ServerOptions};
 
 // TODO: clippy https://github.com/Manishearth/rust-clippy
 
+const DEFAULT_ADDRESS: &'static str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 3000;
+const DEFAULT_LOG_CONFIG: &'static str = "log4rs.yml";
+
+const USAGE: &'static str = "Usage: stubby [options] [STUB_DIR...]
+
+Options (and environment variables):
+    -a, --address ADDR      address to listen on (STUBBY_ADDRESS, default 127.0.0.1)
+    -p, --port PORT         port to listen on, 0 for any free port (STUBBY_PORT, default 3000)
+    -s, --stubs DIR         load and watch stub files in DIR, may be repeated (STUBBY_STUBS, a path list)
+        --log-config FILE   log4rs config file (STUBBY_LOG_CONFIG, default log4rs.yml if it exists,
+                            otherwise logs go to stderr)
+        --log-level LEVEL   off, error, warn, info, debug or trace (STUBBY_LOG_LEVEL)
+        --print-port        print the port listened on to stdout, once ready, and nothing else:
+                            console logging goes to stderr instead
+        --port-file FILE    write the port listened on to FILE, once ready (STUBBY_PORT_FILE)
+    -h, --help              show this message";
+
+/// Command line options, which override the matching environment variables.
+#[derive(Debug, PartialEq)]
+struct Options {
+    address: IpAddr,
+    port: u16,
+    stub_dirs: Vec<PathBuf>,
+    log_config: Option<PathBuf>,
+    log_level: Option<LogLevelFilter>,
+    print_port: bool,
+    port_file: Option<PathBuf>,
+    help: bool
+}
+
+fn parse_options<I, E>(args: I, env: E) -> Result<Options, String>
+    where I: IntoIterator<Item = String>,
+          E: Fn(&str) -> Option<String>
+{
+    let mut address = env("STUBBY_ADDRESS");
+    let mut port = env("STUBBY_PORT");
+    let mut stub_dirs: Vec<PathBuf> = env("STUBBY_STUBS").map_or(vec![], |s| env::split_paths(&s).collect());
+    let mut log_config = env("STUBBY_LOG_CONFIG").map(PathBuf::from);
+    let mut log_level = env("STUBBY_LOG_LEVEL");
+    let mut print_port = false;
+    let mut port_file = env("STUBBY_PORT_FILE").map(PathBuf::from);
+    let mut help = false;
+
+    let mut cli_stub_dirs = vec![]; // replace those from the environment, if any given
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("Missing value for {}", name));
+        match arg.as_str() {
+            "-a" | "--address" => address = Some(value(&arg)?),
+            "-p" | "--port" => port = Some(value(&arg)?),
+            "-s" | "--stubs" => cli_stub_dirs.push(PathBuf::from(value(&arg)?)),
+            "--log-config" => log_config = Some(PathBuf::from(value(&arg)?)),
+            "--log-level" => log_level = Some(value(&arg)?),
+            "--print-port" => print_port = true,
+            "--port-file" => port_file = Some(PathBuf::from(value(&arg)?)),
+            "-h" | "--help" => help = true,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ => cli_stub_dirs.push(PathBuf::from(&arg)),
+        }
+    }
+    if !cli_stub_dirs.is_empty() {
+        stub_dirs = cli_stub_dirs;
+    }
+
+    let address = address.as_ref().map_or(DEFAULT_ADDRESS, |a| a.as_str());
+    let port = match port {
+        Some(p) => p.parse::<u16>().map_err(|_| format!("Invalid port: {}", p))?,
+        None => DEFAULT_PORT,
+    };
+    let log_level = match log_level {
+        Some(l) => Some(l.parse::<LogLevelFilter>().map_err(|_| format!("Invalid log level: {}", l))?),
+        None => None,
+    };
+
+    Ok(Options {
+        address: address.parse().map_err(|_| format!("Invalid address: {}", address))?,
+        port: port,
+        stub_dirs: stub_dirs,
+        log_config: log_config,
+        log_level: log_level,
+        print_port: print_port,
+        port_file: port_file,
+        help: help
+    })
+}
+
+/// The `console` appender of a log config file, but always writing to stderr, to keep stdout for `--print-port`.
+struct StderrConsoleDeserializer;
+
+#[derive(Deserialize)]
+struct ConsoleConfig {
+    encoder: Option<EncoderConfig> // target is ignored
+}
+
+impl Deserialize for StderrConsoleDeserializer {
+    type Trait = Append;
+
+    type Config = ConsoleConfig;
+
+    fn deserialize(&self,
+                   config: ConsoleConfig,
+                   deserializers: &Deserializers)
+                   -> Result<Box<Append>, Box<Error + Sync + Send>> {
+        let mut appender = ConsoleAppender::builder().target(Target::Stderr);
+        if let Some(encoder) = config.encoder {
+            appender = appender.encoder(deserializers.deserialize(&encoder.kind, encoder.config)?);
+        }
+        Ok(Box::new(appender.build()))
+    }
+}
+
+/// Use the log config file if there is one, with its root level replaced by `level` if given.
+/// Otherwise log to stderr.
+fn init_logging(log_config: Option<&Path>, level: Option<LogLevelFilter>, stderr_only: bool) -> Result<(), String> {
+    let default = Path::new(DEFAULT_LOG_CONFIG);
+    let file = log_config.or_else(|| if default.exists() { Some(default) } else { None });
+
+    let mut deserializers = Deserializers::default();
+    if stderr_only {
+        deserializers.insert("console", StderrConsoleDeserializer);
+    }
+
+    let config = match (file, level) {
+        (Some(file), None) => {
+            return log4rs::init_file(file, deserializers)
+                .map_err(|e| format!("Error loading log config {}: {}", file.display(), e))
+        }
+        (Some(file), Some(level)) => {
+            let raw = read_log_config(file).map_err(|e| format!("Error loading log config {}: {}", file.display(), e))?;
+            let (appenders, errors) = raw.appenders_lossy(&deserializers);
+            if let Some(e) = errors.into_iter().next() {
+                return Err(format!("Error loading log config {}: {}", file.display(), e));
+            }
+            let root = Root::builder().appenders(raw.root().appenders().iter().cloned()).build(level);
+            Config::builder().appenders(appenders).loggers(raw.loggers()).build(root)
+        }
+        (None, level) => {
+            let stderr = ConsoleAppender::builder().target(Target::Stderr).build();
+            let stderr = Appender::builder().build("stderr", Box::new(stderr));
+            Config::builder().appender(stderr).build(Root::builder().appender("stderr").build(level.unwrap_or(LogLevelFilter::Info)))
+        }
+    };
+
+    let config = config.map_err(|e| format!("Invalid log config: {:?}", e))?;
+    log4rs::init_config(config).map(|_| ()).map_err(|e| format!("Error initialising logging: {}", e))
+}
+
+fn read_log_config(file: &Path) -> Result<RawConfig, String> {
+    let mut content = String::new();
+    File::open(file).and_then(|mut f| f.read_to_string(&mut content)).map_err(|e| e.to_string())?;
+    serde_yaml::from_str(&content).map_err(|e| e.to_string())
+}
+
+/// Let whoever started the server know which port it got, eg: when started on port 0.
+fn report_port(options: &Options, port: u16) -> io::Result<()> {
+    if options.print_port {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        writeln!(out, "{}", port)?;
+        out.flush()?;
+    }
+    if let Some(ref path) = options.port_file {
+        write_port_file(path, port)?;
+    }
+    Ok(())
+}
+
+/// Write to a temporary file first, so anyone polling for the port file never reads it half written.
+fn write_port_file(path: &Path, port: u16) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    File::create(&temp)?.write_all(port.to_string().as_bytes())?;
+    fs::rename(&temp, path)
+}
+
 fn main() {
-    log4rs::init_file("log4rs.yml", Default::default()).unwrap();
+    let options = match parse_options(env::args().skip(1), |name| env::var(name).ok()) {
+        Ok(o) => o,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    if options.help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let log_config = options.log_config.as_ref().map(|p| p.as_path());
+    if let Err(message) = init_logging(log_config, options.log_level, options.print_port) {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
 
     info!("Starting up...");
- 
-    let addr = "127.0.0.1:3000".parse().unwrap();
 
-    let server = match Server::start(addr) {
+    let addr = SocketAddr::new(options.address, options.port);
+
+    let server_options = ServerOptions {
+        stub_dirs: options.stub_dirs.clone(),
+        watch_interval: None
+    };
+
+    let server = match Server::start_with(addr, server_options) {
         Ok(s) => s,
         Err(e) => {
             error!("Error starting server: {:?}", e);
-            return;
+            process::exit(1);
         }
     };
-    
+
     info!("Listening on http://{}...", server.local_addr());
 
+    if let Err(e) = report_port(&options, server.local_addr().port()) {
+        error!("Error reporting port: {}", e);
+    }
+
     match server.join() {
         Ok(_) => {
             info!("Server finished");
         },
         Err(e) => {
             error!("Server ended with error: {:?}", e);
-            return;
+            process::exit(1);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::net::IpAddr;
+    use std::path::PathBuf;
+
+    use log::LogLevelFilter;
+
+    use {parse_options, write_port_file, Options};
+
+    fn parse(args: &[&str], env: &[(&str, &str)]) -> Result<Options, String> {
+        let env: Vec<(String, String)> = env.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect();
+        parse_options(args.iter().map(|a| a.to_string()),
+                      |name| env.iter().find(|e| e.0 == name).map(|e| e.1.clone()))
+    }
+
+    #[test]
+    fn test_defaults() {
+        let options = parse(&[], &[]).unwrap();
+        assert_eq!(options.address, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(options.port, 3000);
+        assert!(options.stub_dirs.is_empty());
+        assert_eq!(options.log_level, None);
+        assert!(!options.print_port);
+    }
+
+    #[test]
+    fn test_args_override_env() {
+        let env = [("STUBBY_PORT", "4000"), ("STUBBY_ADDRESS", "0.0.0.0"), ("STUBBY_STUBS", "env1:env2")];
+        assert_eq!(parse(&[], &env).unwrap().stub_dirs, vec![PathBuf::from("env1"), PathBuf::from("env2")]);
+
+        let options = parse(&["--port", "0", "-s", "stubs", "more-stubs", "--log-level", "debug", "--print-port"],
+                            &env).unwrap();
+        assert_eq!(options.address, "0.0.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(options.port, 0);
+        assert_eq!(options.stub_dirs, vec![PathBuf::from("stubs"), PathBuf::from("more-stubs")]);
+        assert_eq!(options.log_level, Some(LogLevelFilter::Debug));
+        assert!(options.print_port);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(parse(&["--port", "http"], &[]).is_err());
+        assert!(parse(&["--port"], &[]).is_err());
+        assert!(parse(&["--verbose"], &[]).is_err());
+        assert!(parse(&[], &[("STUBBY_LOG_LEVEL", "loud")]).is_err());
+        assert!(parse(&["--address", "localhost:80"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_write_port_file() {
+        let path = env::temp_dir().join("stubby-port-file-test");
+        write_port_file(&path, 4321).unwrap();
+
+        let mut content = String::new();
+        File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "4321");
+        assert!(!env::temp_dir().join("stubby-port-file-test.tmp").exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use serde_json::{self, Value};
+use url::form_urlencoded;
+use xml::reader::{ParserConfig, XmlEvent};
+
+use core::pattern::{PatternError, TextPattern};
+
+pub const BODY_TYPE_TEXT: &'static str = "text";
+pub const BODY_TYPE_REGEX: &'static str = "regex";
+pub const BODY_TYPE_JSON: &'static str = "json";
+pub const BODY_TYPE_FORM: &'static str = "form";
+pub const BODY_TYPE_XML: &'static str = "xml";
+
+/// How a stubbed request body is compared with the actual body, chosen by `StubRequest::body_type`:
+///
+/// * `text` - exact match
+/// * `regex` - fully anchored regular expression
+/// * `json` - deep subset match, extra object fields in the actual body are allowed
+/// * `form` - url-encoded fields, in any order
+/// * `xml` - documents compared ignoring whitespace and attribute order
+///
+/// When no type is given, a body that is a JSON object or array (eg, given inline in the stub) is
+/// matched as `json`, otherwise as `text`.
+#[derive(Clone, Debug)]
+pub enum BodyPattern {
+    Text(Vec<u8>),
+    Regex(TextPattern),
+    Json(Value),
+    Form(Vec<(String, String)>),
+    Xml(Vec<XmlNode>)
+}
+
+impl BodyPattern {
+    pub fn new(body: &[u8], body_type: Option<&str>) -> Result<BodyPattern, PatternError> {
+        let body_type = match body_type {
+            Some(t) => t,
+            None => default_body_type(body),
+        };
+        match body_type {
+            BODY_TYPE_TEXT => Ok(BodyPattern::Text(body.to_vec())),
+            BODY_TYPE_REGEX => Ok(BodyPattern::Regex(TextPattern::new(&String::from_utf8_lossy(body))?)),
+            BODY_TYPE_JSON => {
+                serde_json::from_slice(body)
+                    .map(BodyPattern::Json)
+                    .map_err(|e| PatternError::Body(format!("Invalid JSON body: {}", e)))
+            }
+            BODY_TYPE_FORM => Ok(BodyPattern::Form(parse_form(body))),
+            BODY_TYPE_XML => {
+                parse_xml(body)
+                    .map(BodyPattern::Xml)
+                    .map_err(|e| PatternError::Body(format!("Invalid XML body: {}", e)))
+            }
+            other => Err(PatternError::Body(format!("Unknown body type: {}", other))),
+        }
+    }
+
+    pub fn matches(&self, actual: Option<&[u8]>) -> bool {
+        let actual = actual.unwrap_or(&[]);
+        match *self {
+            BodyPattern::Text(ref expected) => expected.as_slice() == actual,
+            BodyPattern::Regex(ref pattern) => pattern.matches(&String::from_utf8_lossy(actual)),
+            BodyPattern::Json(ref expected) => {
+                serde_json::from_slice::<Value>(actual).map(|a| json_subset(expected, &a)).unwrap_or(false)
+            }
+            BodyPattern::Form(ref expected) => *expected == parse_form(actual),
+            BodyPattern::Xml(ref expected) => parse_xml(actual).map(|a| *expected == a).unwrap_or(false),
+        }
+    }
+}
+
+fn default_body_type(body: &[u8]) -> &'static str {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(_)) | Ok(Value::Array(_)) => BODY_TYPE_JSON,
+        _ => BODY_TYPE_TEXT,
+    }
+}
+
+/// True if every field in `expected` is also in `actual` (arrays must match element for element).
+fn json_subset(expected: &Value, actual: &Value) -> bool {
+    match (expected, actual) {
+        (&Value::Object(ref e), &Value::Object(ref a)) => {
+            e.iter().all(|(k, v)| a.get(k).map_or(false, |av| json_subset(v, av)))
+        }
+        (&Value::Array(ref e), &Value::Array(ref a)) => {
+            e.len() == a.len() && e.iter().zip(a.iter()).all(|(ev, av)| json_subset(ev, av))
+        }
+        (e, a) => e == a,
+    }
+}
+
+/// Form fields in a canonical (sorted) order.
+fn parse_form(body: &[u8]) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = form_urlencoded::parse(body).into_owned().collect();
+    fields.sort();
+    fields
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum XmlNode {
+    Element {
+        name: (Option<String>, String), // (namespace, local name)
+        attributes: Vec<((Option<String>, String), String)>, // sorted
+        children: Vec<XmlNode>
+    },
+    Text(String)
+}
+
+/// Parse a document into a tree that compares equal regardless of whitespace and attribute order.
+fn parse_xml(body: &[u8]) -> Result<Vec<XmlNode>, String> {
+    let reader = ParserConfig::new()
+        .trim_whitespace(true)
+        .ignore_comments(true)
+        .cdata_to_characters(true)
+        .coalesce_characters(true)
+        .create_reader(body);
+
+    let mut stack: Vec<XmlNode> = vec![];
+    let mut roots: Vec<XmlNode> = vec![];
+    for event in reader {
+        match event.map_err(|e| e.to_string())? {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                let mut attributes: Vec<_> = attributes.into_iter()
+                    .map(|a| ((a.name.namespace, a.name.local_name), a.value))
+                    .collect();
+                attributes.sort();
+                stack.push(XmlNode::Element {
+                    name: (name.namespace, name.local_name),
+                    attributes: attributes,
+                    children: vec![]
+                });
+            }
+            XmlEvent::EndElement { .. } => {
+                let element = stack.pop().expect("Unbalanced XML events");
+                match stack.last_mut() {
+                    Some(&mut XmlNode::Element { ref mut children, .. }) => children.push(element),
+                    _ => roots.push(element),
+                }
+            }
+            XmlEvent::Characters(text) => {
+                if let Some(&mut XmlNode::Element { ref mut children, .. }) = stack.last_mut() {
+                    children.push(XmlNode::Text(text));
+                }
+            }
+            _ => {} // whitespace, comments, processing instructions, etc.
+        }
+    }
+    Ok(roots)
+}
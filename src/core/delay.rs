@@ -0,0 +1,102 @@
+use rand::{self, Rng};
+use rand::distributions::{IndependentSample, LogNormal, Range};
+
+/// How long to wait (in milliseconds) before sending a stubbed response. Either a plain number
+/// (as the Java server accepted), or an object describing a distribution:
+///
+/// * `{"type": "fixed", "ms": 100}`
+/// * `{"type": "uniform", "min": 100, "max": 500}`
+/// * `{"type": "lognormal", "median": 100, "sigma": 0.5}` (sigma of the underlying normal distribution)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Delay {
+    Fixed(u32),
+    Distribution(DelayDistribution)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DelayDistribution {
+    Fixed { ms: u32 },
+    Uniform { min: u32, max: u32 },
+    LogNormal { median: f64, sigma: f64 }
+}
+
+impl Delay {
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Delay::Distribution(DelayDistribution::Uniform { min, max }) if min > max => {
+                Err(format!("Invalid uniform delay: min ({}) is greater than max ({})", min, max))
+            }
+            Delay::Distribution(DelayDistribution::LogNormal { median, sigma }) if !(median > 0.0 && sigma >= 0.0) => {
+                Err(format!("Invalid log-normal delay: median ({}) must be positive and sigma ({}) not negative",
+                            median,
+                            sigma))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Pick a delay in milliseconds (assumes `validate()` has passed).
+    pub fn sample(&self) -> u32 {
+        self.sample_with(&mut rand::thread_rng())
+    }
+
+    fn sample_with<R: Rng>(&self, rng: &mut R) -> u32 {
+        match *self {
+            Delay::Fixed(ms) => ms,
+            Delay::Distribution(DelayDistribution::Fixed { ms }) => ms,
+            Delay::Distribution(DelayDistribution::Uniform { min, max }) => {
+                if min == max {
+                    min
+                } else {
+                    Range::new(min as u64, max as u64 + 1).ind_sample(rng) as u32
+                }
+            }
+            Delay::Distribution(DelayDistribution::LogNormal { median, sigma }) => {
+                let ms = LogNormal::new(median.ln(), sigma).ind_sample(rng);
+                ms.round().min(u32::MAX as f64) as u32
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use core::delay::{Delay, DelayDistribution};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(serde_json::from_str::<Delay>("250").unwrap(), Delay::Fixed(250));
+        assert_eq!(serde_json::from_str::<Delay>(r#"{"type": "uniform", "min": 10, "max": 20}"#).unwrap(),
+                   Delay::Distribution(DelayDistribution::Uniform { min: 10, max: 20 }));
+        assert_eq!(serde_json::from_str::<Delay>(r#"{"type": "lognormal", "median": 100, "sigma": 0.5}"#).unwrap(),
+                   Delay::Distribution(DelayDistribution::LogNormal { median: 100.0, sigma: 0.5 }));
+        assert!(serde_json::from_str::<Delay>(r#"{"type": "gaussian"}"#).is_err());
+
+        assert_eq!(serde_json::to_string(&Delay::Fixed(250)).unwrap(), "250");
+    }
+
+    #[test]
+    fn test_sample() {
+        assert_eq!(Delay::Fixed(250).sample(), 250);
+
+        let uniform = Delay::Distribution(DelayDistribution::Uniform { min: 10, max: 20 });
+        for _ in 0..100 {
+            let ms = uniform.sample();
+            assert!(ms >= 10 && ms <= 20);
+        }
+
+        let fixed = Delay::Distribution(DelayDistribution::LogNormal { median: 100.0, sigma: 0.0 });
+        assert_eq!(fixed.sample(), 100);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Delay::Distribution(DelayDistribution::Uniform { min: 20, max: 10 }).validate().is_err());
+        assert!(Delay::Distribution(DelayDistribution::LogNormal { median: 0.0, sigma: 1.0 }).validate().is_err());
+        assert!(Delay::Distribution(DelayDistribution::LogNormal { median: 10.0, sigma: -1.0 }).validate().is_err());
+        assert!(Delay::Distribution(DelayDistribution::LogNormal { median: 10.0, sigma: 1.0 }).validate().is_ok());
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use serde_json;
+use serde_yaml;
+
+use core::StubExchange;
+use core::service::{ServiceError, StubService};
+
+#[derive(Debug)]
+pub enum FileError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Yaml(serde_yaml::Error),
+    Service(ServiceError)
+}
+
+impl From<io::Error> for FileError {
+    fn from(e: io::Error) -> FileError {
+        FileError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for FileError {
+    fn from(e: serde_json::Error) -> FileError {
+        FileError::Json(e)
+    }
+}
+
+impl From<serde_yaml::Error> for FileError {
+    fn from(e: serde_yaml::Error) -> FileError {
+        FileError::Yaml(e)
+    }
+}
+
+impl From<ServiceError> for FileError {
+    fn from(e: ServiceError) -> FileError {
+        FileError::Service(e)
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FileError::Io(ref e) => write!(f, "{}", e),
+            FileError::Json(ref e) => write!(f, "Invalid JSON: {}", e),
+            FileError::Yaml(ref e) => write!(f, "Invalid YAML: {}", e),
+            FileError::Service(ServiceError::NotFound(ref message)) |
+            FileError::Service(ServiceError::InvalidPattern(ref message)) |
+            FileError::Service(ServiceError::InvalidExchange(ref message)) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Whether the file name ends in `.json`, `.yaml` or `.yml`.
+pub fn is_stub_file(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") | Some("yaml") | Some("yml") => path.is_file(),
+        _ => false,
+    }
+}
+
+/// The stub files directly in `dir`, sorted by name.
+pub fn list_stub_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_stub_file(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// A stub file holds either a single `StubExchange` or a list of them.
+pub fn read_exchanges(path: &Path) -> Result<Vec<StubExchange>, FileError> {
+    let mut content = String::new();
+    File::open(path)?.read_to_string(&mut content)?;
+
+    if path.extension().map_or(false, |e| e == "json") {
+        match serde_json::from_str::<serde_json::Value>(&content)? {
+            json @ serde_json::Value::Array(_) => Ok(serde_json::from_value(json)?),
+            json => Ok(vec![serde_json::from_value(json)?]),
+        }
+    } else {
+        match serde_yaml::from_str::<serde_yaml::Value>(&content)? {
+            yaml @ serde_yaml::Value::Sequence(_) => Ok(serde_yaml::from_value(yaml)?),
+            yaml => Ok(vec![serde_yaml::from_value(yaml)?]),
+        }
+    }
+}
+
+/// Size and modification time, to tell when a file has changed.
+type Signature = Option<(Option<SystemTime>, u64)>;
+
+fn signature(path: &Path) -> Signature {
+    fs::metadata(path).ok().map(|m| (m.modified().ok(), m.len()))
+}
+
+struct LoadedFile {
+    signature: Signature,
+    exchanges: usize,
+    error: Option<String> // from the last attempt to load the file
+}
+
+/// A stub file, as shown by `/_control/files`.
+#[derive(Clone, Debug, Serialize)]
+pub struct FileStatus {
+    pub path: String,
+    pub exchanges: usize,
+    pub error: Option<String>
+}
+
+/// Loads stubbed exchanges from files in the given directories, and keeps them up to date as the files change.
+/// Only exchanges loaded from files are ever replaced or removed.
+pub struct FileSource {
+    dirs: Vec<PathBuf>,
+    stub_service: Arc<StubService>,
+    files: Mutex<BTreeMap<PathBuf, LoadedFile>>
+}
+
+impl FileSource {
+    pub fn new(dirs: Vec<PathBuf>, stub_service: Arc<StubService>) -> FileSource {
+        FileSource {
+            dirs: dirs,
+            stub_service: stub_service,
+            files: Mutex::new(BTreeMap::new())
+        }
+    }
+
+    /// Load every stub file, returning how many exchanges were added. Files with errors are logged and skipped,
+    /// but a directory that can't be read is an error.
+    pub fn load_initial_files(&self) -> io::Result<usize> {
+        for dir in &self.dirs {
+            fs::read_dir(dir)?;
+        }
+        self.reload_changed();
+        Ok(self.get_files().iter().map(|f| f.exchanges).sum())
+    }
+
+    /// Load new and changed files, and remove the exchanges of deleted files, returning how many files changed.
+    /// If a file can't be loaded, the exchanges it had before are kept.
+    pub fn reload_changed(&self) -> usize {
+        let mut found = BTreeMap::new();
+        for dir in &self.dirs {
+            match list_stub_files(dir) {
+                Ok(paths) => found.extend(paths.into_iter().map(|p| { let s = signature(&p); (p, s) })),
+                Err(e) => warn!("Error reading stub directory {}: {}", dir.display(), e),
+            }
+        }
+
+        let mut files = self.files.lock().unwrap();
+        let mut changed = 0;
+
+        let deleted: Vec<PathBuf> = files.keys().filter(|p| !found.contains_key(*p)).cloned().collect();
+        for path in deleted {
+            files.remove(&path);
+            let _ = self.stub_service.replace_responses_from(&path.display().to_string(), vec![]); // can't fail
+            info!("Removed stubbed exchanges from deleted file {}", path.display());
+            changed += 1;
+        }
+
+        for (path, signature) in found {
+            if files.get(&path).map_or(false, |f| f.signature == signature) {
+                continue; // unchanged
+            }
+            let loaded = match self.load_file(&path) {
+                Ok(n) => {
+                    info!("Loaded {} stubbed exchange(s) from {}", n, path.display());
+                    LoadedFile { signature: signature, exchanges: n, error: None }
+                }
+                Err(e) => {
+                    error!("Error loading {}: {}", path.display(), e);
+                    LoadedFile {
+                        signature: signature,
+                        exchanges: files.get(&path).map_or(0, |f| f.exchanges),
+                        error: Some(e.to_string())
+                    }
+                }
+            };
+            files.insert(path, loaded);
+            changed += 1;
+        }
+
+        changed
+    }
+
+    pub fn get_files(&self) -> Vec<FileStatus> {
+        let files = self.files.lock().unwrap();
+        files.iter()
+            .map(|(path, f)| {
+                FileStatus {
+                    path: path.display().to_string(),
+                    exchanges: f.exchanges,
+                    error: f.error.clone()
+                }
+            })
+            .collect()
+    }
+
+    fn load_file(&self, path: &Path) -> Result<usize, FileError> {
+        let exchanges = read_exchanges(path)?;
+        Ok(self.stub_service.replace_responses_from(&path.display().to_string(), exchanges)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use core::{StubExchange, StubRequest, StubResponse};
+    use core::file::{read_exchanges, FileSource};
+    use core::service::StubService;
+
+    fn exchange(path: &str) -> StubExchange {
+        StubExchange {
+            request: StubRequest { path: Some(path.to_owned()), ..Default::default() },
+            response: Some(StubResponse { status: 200, ..Default::default() }),
+            ..Default::default()
+        }
+    }
+
+    fn request(path: &str) -> StubRequest {
+        StubRequest { path: Some(path.to_owned()), ..Default::default() }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("stubby-files-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(dir: &PathBuf, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_read_exchanges() {
+        let dir = temp_dir("read");
+        let one = write(&dir, "one.json", r#"{"request": {"path": "/one"}, "response": {"status": 200}}"#);
+        let many = write(&dir, "many.yaml", r#"
+- request:
+    path: /two
+  response:
+    status: 201
+    body:
+      id: 2
+- request:
+    path: /three
+  response:
+    status: 202
+"#);
+        let invalid = write(&dir, "invalid.json", r#"{"request": {}}"#);
+
+        assert_eq!(read_exchanges(&one).unwrap().len(), 1);
+        let exchanges = read_exchanges(&many).unwrap();
+        assert_eq!(exchanges.len(), 2);
+        assert_eq!(exchanges[0].response.as_ref().unwrap().body, Some(b"{\"id\":2}".to_vec()));
+        assert!(read_exchanges(&invalid).is_ok()); // missing response is found when added
+        assert!(read_exchanges(&dir.join("missing.json")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_initial_files() {
+        let dir = temp_dir("load");
+        write(&dir, "a.json", r#"[{"request": {"path": "/a"}, "response": {"status": 200}}]"#);
+        write(&dir, "b.yml", "request: {path: /b}\nresponse: {status: 200}\n");
+        write(&dir, "c.json", "not json");
+        write(&dir, "d.txt", "ignored");
+
+        let service = Arc::new(StubService::new());
+        let source = FileSource::new(vec![dir.clone()], service.clone());
+        assert_eq!(source.load_initial_files().unwrap(), 2);
+        assert!(service.find_match(&request("/b")).matched());
+
+        assert!(FileSource::new(vec![dir.join("missing")], service).load_initial_files().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload_changed() {
+        let dir = temp_dir("reload");
+        let service = Arc::new(StubService::new());
+        service.add_response(exchange("/api")).unwrap();
+        let source = FileSource::new(vec![dir.clone()], service.clone());
+
+        write(&dir, "a.json", r#"{"request": {"path": "/a"}, "response": {"status": 200}}"#);
+        assert_eq!(source.reload_changed(), 1);
+        assert_eq!(source.reload_changed(), 0);
+        assert!(service.find_match(&request("/a")).matched());
+
+        write(&dir, "a.json", r#"{"request": {"path": "/bb"}, "response": {"status": 200}}"#); // different size
+        assert_eq!(source.reload_changed(), 1);
+        assert!(!service.find_match(&request("/a")).matched());
+        assert!(service.find_match(&request("/bb")).matched());
+
+        write(&dir, "a.json", r#"{"request": {"path": "/c"}"#);
+        assert_eq!(source.reload_changed(), 1);
+        assert!(service.find_match(&request("/bb")).matched()); // kept, as new file is invalid
+        let files = source.get_files();
+        assert_eq!(files[0].exchanges, 1);
+        assert!(files[0].error.is_some());
+
+        fs::remove_file(dir.join("a.json")).unwrap();
+        assert_eq!(source.reload_changed(), 1);
+        assert!(!service.find_match(&request("/bb")).matched());
+        assert!(source.get_files().is_empty());
+        assert!(service.find_match(&request("/api")).matched()); // not from a file
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
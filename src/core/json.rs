@@ -0,0 +1,106 @@
+//! Serde helpers for the JSON wire format shared with the Java http-stub-server.
+
+use serde::{Deserialize, Deserializer, Serializer};
+use serde_json::{self, Value};
+
+/// Bodies are sent as a string, or as inline JSON (which is kept in its serialised form).
+pub fn deserialize_body<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
+    where D: Deserializer<'de>
+{
+    match Value::deserialize(deserializer)? {
+        Value::Null => Ok(None),
+        Value::String(s) => Ok(Some(s.into_bytes())),
+        value => Ok(Some(serde_json::to_vec(&value).expect("Serialising JSON value"))),
+    }
+}
+
+pub fn serialize_body<S>(body: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    match *body {
+        None => serializer.serialize_none(),
+        Some(ref bytes) => serializer.serialize_str(&String::from_utf8_lossy(bytes)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use core::{Fault, Sequence, StubExchange, StubResponse};
+    use core::delay::Delay;
+
+    #[test]
+    fn test_java_exchange() {
+        let json = r#"{
+            "request": {
+                "method": "POST",
+                "path": "/orders",
+                "params": [{"name": "foo", "value": "bar"}],
+                "headers": [{"name": "Content-Type", "value": "application/json"}],
+                "body": {"id": 123}
+            },
+            "response": {
+                "status": 201,
+                "headers": [{"name": "Location", "value": "/orders/123"}],
+                "body": "created"
+            },
+            "delay": 100
+        }"#;
+
+        let exchange: StubExchange = serde_json::from_str(json).unwrap();
+
+        assert_eq!(exchange.request.method, Some("POST".to_owned()));
+        assert_eq!(exchange.request.get_param("FOO"), Some("bar"));
+        assert_eq!(exchange.request.headers[0].name, "Content-Type");
+        assert_eq!(exchange.request.body, Some(b"{\"id\":123}".to_vec()));
+        let response = exchange.response.as_ref().unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body, Some(b"created".to_vec()));
+        assert_eq!(exchange.delay, Some(Delay::Fixed(100)));
+
+        let out = serde_json::to_value(&exchange).unwrap();
+        assert_eq!(out["request"]["body"], "{\"id\":123}");
+        assert_eq!(out["response"]["body"], "created");
+    }
+
+    #[test]
+    fn test_minimal_exchange() {
+        let exchange: StubExchange = serde_json::from_str(r#"{"request": {}, "response": {"status": 204}}"#).unwrap();
+
+        assert_eq!(exchange.request.path, None);
+        assert!(exchange.request.params.is_empty());
+        assert_eq!(exchange.response.unwrap().body, None);
+        assert!(exchange.responses.is_empty());
+        assert_eq!(exchange.delay, None);
+    }
+
+    #[test]
+    fn test_response_sequence() {
+        let json = r#"{
+            "request": {"path": "/retry"},
+            "responses": [{"status": 500}, {"status": 200}],
+            "sequence": "repeat_last"
+        }"#;
+        let exchange: StubExchange = serde_json::from_str(json).unwrap();
+
+        assert!(exchange.response.is_none());
+        assert_eq!(exchange.responses.len(), 2);
+        assert_eq!(exchange.sequence, Some(Sequence::RepeatLast));
+
+        let out = serde_json::to_value(&exchange).unwrap();
+        assert!(out.get("response").is_none());
+        assert_eq!(out["sequence"], "repeat_last");
+    }
+
+    #[test]
+    fn test_fault() {
+        let response: StubResponse = serde_json::from_str(r#"{"status": 200, "fault": "reset_connection"}"#).unwrap();
+        assert_eq!(response.fault, Some(Fault::ResetConnection));
+
+        let response: StubResponse =
+            serde_json::from_str(r#"{"status": 200, "fault": {"drip": {"bytes": 1, "interval_ms": 50}}}"#).unwrap();
+        assert_eq!(response.fault, Some(Fault::Drip { bytes: 1, interval_ms: 50 }));
+
+        assert!(serde_json::from_str::<StubResponse>(r#"{"status": 200, "fault": "explode"}"#).is_err());
+    }
+}
@@ -1,22 +1,34 @@
-use std::ascii::AsciiExt;
+use url::Url;
 
+use core::delay::Delay;
+
+pub mod body;
+pub mod delay;
+pub mod file;
+mod json;
+pub mod pattern;
+pub mod script;
 pub mod service;
+pub mod template;
 
-struct StubParam {
-    name: String,
-    value: String
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StubParam {
+    pub name: String,
+    pub value: String
 }
 
-trait StubMessage {
+pub trait StubMessage {
     fn headers(&self) -> &Vec<StubParam>;
     fn body(&self) -> Option<&Vec<u8>>;
 
-    /*
-      def getHeader(name: String): Option[String] =
-        headers.find(_.name.equalsIgnoreCase(name)).map(_.value)
-      def getHeaders(name: String): Seq[String] =
-        headers.filter(_.name.equalsIgnoreCase(name)).map(_.value)
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers().iter().find(|_h| _h.name.eq_ignore_ascii_case(name)).map(|_h| &_h.value as &str)
+    }
+    fn get_headers(&self, name: &str) -> Vec<&str> {
+        self.headers().iter().filter(|_h| _h.name.eq_ignore_ascii_case(name)).map(|_h| &_h.value as &str).collect()
+    }
 
+    /*
       def addHeader(name: String, value: String): T =
         copyWith(headers :+ StubParam(name, value))
       def removeHeader(name: String): T =
@@ -26,21 +38,27 @@ trait StubMessage {
     */
 }
 
-#[derive(Default)]
-struct StubRequest {
-    method: Option<String>,
-    path: Option<String>,
-    params: Vec<StubParam>,
-    headers: Vec<StubParam>,
-    body: Option<Vec<u8>>,
-    body_type: Option<String>
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StubRequest {
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub params: Vec<StubParam>,
+    #[serde(default)]
+    pub headers: Vec<StubParam>,
+    #[serde(default, serialize_with = "json::serialize_body", deserialize_with = "json::deserialize_body")]
+    pub body: Option<Vec<u8>>,
+    #[serde(default)]
+    pub body_type: Option<String>
 }
 
 impl StubRequest {
-    fn get_param(&self, name: &str) -> Option<&str> {
+    pub fn get_param(&self, name: &str) -> Option<&str> {
         self.params.iter().find(|_p| _p.name.eq_ignore_ascii_case(name)).as_ref().map(|_p| &_p.value as &str)
     }
-    fn get_params(&self, name: &str) -> Vec<&str> {
+    pub fn get_params(&self, name: &str) -> Vec<&str> {
         self.params.iter().filter(|_p| _p.name.eq_ignore_ascii_case(name)).map(|_p| &_p.value as &str).collect()
     }
 }
@@ -54,11 +72,43 @@ impl StubMessage for StubRequest {
     }
 }
 
-#[derive(Default)]
-struct StubResponse {
-    status: u16,
-    headers: Vec<StubParam>,
-    body: Option<Vec<u8>>
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StubResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<StubParam>,
+    #[serde(default, serialize_with = "json::serialize_body", deserialize_with = "json::deserialize_body")]
+    pub body: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub template: bool, // render `{{...}}` expressions in body and header values
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<StubChunk>, // streamed with `Transfer-Encoding: chunked` instead of sending `body`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fault: Option<Fault> // break the connection instead of sending the response normally
+}
+
+/// Part of a streamed response body, sent once its delay has passed.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StubChunk {
+    #[serde(default, serialize_with = "json::serialize_body", deserialize_with = "json::deserialize_body")]
+    pub body: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay: Option<Delay>
+}
+
+/// Ways of breaking a connection on purpose, eg: `"reset_connection"` or `{"drip": {"bytes": 1, "interval_ms": 100}}`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    ResetConnection,
+    CloseWithoutResponse,
+    MalformedChunkedBody,
+    RandomGarbage,
+    Drip { bytes: usize, interval_ms: u64 } // send the body a few bytes at a time
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
 }
 
 impl StubMessage for StubResponse {
@@ -70,8 +120,93 @@ impl StubMessage for StubResponse {
     }
 }
 
-struct StubExchange {
-    request: StubRequest,
-    response: StubResponse,
-    delay: Option<u32>
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StubExchange {
+    pub request: StubRequest,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<StubResponse>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub responses: Vec<StubResponse>, // returned in turn, instead of a single `response`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<Sequence>,
+    #[serde(default)]
+    pub delay: Option<Delay>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>, // higher matched first, default 0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub times: Option<u32>, // removed after matching this many requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_after_ms: Option<u64>, // removed this long after being added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<StubProxy>, // forward matching requests instead of giving a stubbed response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scenario: Option<String>, // name of the scenario (state machine) this exchange belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required_state: Option<String>, // only match when the scenario is in this state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_state: Option<String>, // move the scenario to this state when matched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>, // assigned by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String> // file the exchange was loaded from (set by the server)
+}
+
+/// Forward requests to another server, eg: `{"url": "http://localhost:9000", "record": true}`
+///
+/// Only `http` upstream servers are supported. To forward to (or record from) an HTTPS service, run a TLS-terminating
+/// proxy in front of it, eg: `socat TCP-LISTEN:9000,fork OPENSSL:staging.example.com:443`.
+///
+/// The path and added header values can use `{{...}}` template expressions, eg: `"path": "/v2/{{path.1}}"`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StubProxy {
+    pub url: String, // base URL, the request path is appended
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>, // forward to this path instead of the request's
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<StubParam>, // added to (or replacing those in) the forwarded request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>, // replaces the status code of the upstream response
+    #[serde(default)]
+    pub record: bool, // save each request and response as a new `StubExchange`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record_dir: Option<String> // also write recorded exchanges here, one file each
+}
+
+impl StubProxy {
+    pub fn validate(&self) -> Result<(), String> {
+        match Url::parse(&self.url) {
+            Ok(ref url) if url.scheme() == "http" && url.has_host() => Ok(()),
+            Ok(ref url) if url.scheme() == "https" => {
+                Err(format!("Invalid proxy URL: {}: https is not supported, forward to a local http proxy in front of it \
+                             instead (eg: socat or stunnel)", self.url))
+            }
+            Ok(_) => Err(format!("Invalid proxy URL (only http is supported): {}", self.url)),
+            Err(e) => Err(format!("Invalid proxy URL: {}: {}", self.url, e)),
+        }?;
+        match self.status {
+            Some(status) => validate_status(status),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Status codes must be 100 to 599 to be sent at all.
+pub fn validate_status(status: u16) -> Result<(), String> {
+    if (100..600).contains(&status) {
+        Ok(())
+    } else {
+        Err(format!("Invalid status code: {}", status))
+    }
+}
+
+/// What to do once every response in `StubExchange::responses` has been returned.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sequence {
+    #[default]
+    RepeatLast,
+    Cycle,
+    Stop // stop matching requests
 }
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use regex::{self, Regex};
+
+use core::{StubMessage, StubParam, StubRequest};
+use core::body::BodyPattern;
+
+#[derive(Debug)]
+pub enum PatternError {
+    Regex(regex::Error),
+    Body(String)
+}
+
+impl From<regex::Error> for PatternError {
+    fn from(e: regex::Error) -> PatternError {
+        PatternError::Regex(e)
+    }
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatternError::Regex(ref e) => write!(f, "{}", e),
+            PatternError::Body(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// A fully anchored regular expression, along with the pattern it was compiled from.
+#[derive(Clone, Debug)]
+pub struct TextPattern {
+    pattern: String,
+    regex: Regex
+}
+
+impl TextPattern {
+    pub fn new(pattern: &str) -> Result<TextPattern, regex::Error> {
+        Ok(TextPattern {
+            pattern: pattern.to_owned(),
+            regex: Regex::new(&format!("^(?:{})$", pattern))?
+        })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        self.regex.is_match(value)
+    }
+
+    /// Capture groups by index ("0" is the whole value) and by name, if `value` matches.
+    pub fn captures(&self, value: &str) -> Option<HashMap<String, String>> {
+        self.regex.captures(value).map(|caps| {
+            let mut groups = HashMap::new();
+            for (i, name) in self.regex.capture_names().enumerate() {
+                if let Some(m) = caps.get(i) {
+                    groups.insert(i.to_string(), m.as_str().to_owned());
+                    if let Some(name) = name {
+                        groups.insert(name.to_owned(), m.as_str().to_owned());
+                    }
+                }
+            }
+            groups
+        })
+    }
+}
+
+/// Capture groups from the regexes that matched a request, for use in response templates.
+#[derive(Clone, Debug, Default)]
+pub struct RequestCaptures {
+    pub path: HashMap<String, String>,
+    pub params: HashMap<String, HashMap<String, String>> // by lower case param name
+}
+
+/// All of the value patterns given for one (case-insensitive) param or header name.
+#[derive(Clone, Debug)]
+struct ParamPattern {
+    name: String,
+    values: Vec<TextPattern>
+}
+
+impl ParamPattern {
+    /// Group params by name, preserving the order names first appear in.
+    fn compile(params: &[StubParam]) -> Result<Vec<ParamPattern>, regex::Error> {
+        let mut patterns: Vec<ParamPattern> = vec![];
+        for param in params {
+            let value = TextPattern::new(&param.value)?;
+            match patterns.iter().position(|p| p.name.eq_ignore_ascii_case(&param.name)) {
+                Some(i) => patterns[i].values.push(value),
+                None => {
+                    patterns.push(ParamPattern {
+                        name: param.name.clone(),
+                        values: vec![value]
+                    })
+                }
+            }
+        }
+        Ok(patterns)
+    }
+
+    /// Every expected value must match at least one of the actual values for this name.
+    fn match_values(&self, field: &str, actual: &[&str], result: &mut MatchResult) {
+        let field = format!("{}[{}]", field, self.name);
+        for value in &self.values {
+            let found = actual.iter().find(|a| value.matches(a));
+            let actual = match found {
+                Some(a) => Some(a.to_string()),
+                None if actual.is_empty() => None,
+                None => Some(actual.join(", ")),
+            };
+            result.add(&field, found.is_some(), value.pattern(), actual);
+        }
+    }
+}
+
+/// Outcome of testing one field of a request pattern.
+#[derive(Clone, Debug, Serialize)]
+pub struct FieldMatch {
+    pub field: String,
+    pub matches: bool,
+    pub expected: String,
+    pub actual: Option<String>
+}
+
+/// Outcome of testing a request against a pattern, with the result for each field.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct MatchResult {
+    pub matches: bool,
+    pub fields: Vec<FieldMatch>
+}
+
+impl MatchResult {
+    fn add(&mut self, field: &str, matches: bool, expected: &str, actual: Option<String>) {
+        self.fields.push(FieldMatch {
+            field: field.to_owned(),
+            matches: matches,
+            expected: expected.to_owned(),
+            actual: actual
+        });
+    }
+
+    /// Add a check made beyond the request pattern itself, eg: whether a stub has any responses left.
+    pub fn add_condition(&mut self, field: &str, matches: bool, expected: &str, actual: Option<String>) {
+        self.add(field, matches, expected, actual);
+        self.matches = self.matches && matches;
+    }
+}
+
+/// Compiled form of a stubbed `StubRequest`, used to test incoming requests against it.
+#[derive(Clone, Debug)]
+pub struct RequestPattern {
+    method: Option<TextPattern>,
+    path: Option<TextPattern>,
+    params: Vec<ParamPattern>,
+    headers: Vec<ParamPattern>,
+    body: Option<(String, BodyPattern)> // (as given, compiled)
+}
+
+impl RequestPattern {
+    pub fn new(request: &StubRequest) -> Result<RequestPattern, PatternError> {
+        let body = match request.body {
+            Some(ref body) => {
+                let pattern = BodyPattern::new(body, request.body_type.as_ref().map(|t| t as &str))?;
+                Some((String::from_utf8_lossy(body).into_owned(), pattern))
+            }
+            None => None,
+        };
+        Ok(RequestPattern {
+            method: compile_field(&request.method)?,
+            path: compile_field(&request.path)?,
+            params: ParamPattern::compile(&request.params)?,
+            headers: ParamPattern::compile(&request.headers)?,
+            body: body
+        })
+    }
+
+    pub fn matches(&self, actual: &StubRequest) -> bool {
+        self.match_request(actual).matches
+    }
+
+    /// Capture groups for a request that matches this pattern.
+    pub fn captures(&self, actual: &StubRequest) -> RequestCaptures {
+        let mut captures = RequestCaptures::default();
+        if let (&Some(ref pattern), &Some(ref path)) = (&self.path, &actual.path) {
+            captures.path = pattern.captures(path).unwrap_or_default();
+        }
+        for param in &self.params {
+            let actual = actual.get_params(&param.name);
+            let groups = param.values.iter().filter_map(|v| actual.iter().filter_map(|a| v.captures(a)).next()).next();
+            if let Some(groups) = groups {
+                captures.params.insert(param.name.to_ascii_lowercase(), groups);
+            }
+        }
+        captures
+    }
+
+    /// Test every field (without stopping at the first mismatch), for diagnostics.
+    pub fn match_request(&self, actual: &StubRequest) -> MatchResult {
+        let mut result = MatchResult::default();
+
+        match_field("method", &self.method, &actual.method, &mut result);
+        match_field("path", &self.path, &actual.path, &mut result);
+        for param in &self.params {
+            param.match_values("param", &actual.get_params(&param.name), &mut result);
+        }
+        for header in &self.headers {
+            header.match_values("header", &actual.get_headers(&header.name), &mut result);
+        }
+        if let Some((ref expected, ref pattern)) = self.body {
+            let body = actual.body.as_ref().map(|b| b as &[u8]);
+            result.add("body", pattern.matches(body), expected, body.map(|b| String::from_utf8_lossy(b).into_owned()));
+        }
+
+        result.matches = result.fields.iter().all(|f| f.matches);
+        result
+    }
+}
+
+fn compile_field(pattern: &Option<String>) -> Result<Option<TextPattern>, regex::Error> {
+    match *pattern {
+        None => Ok(None),
+        Some(ref p) => TextPattern::new(p).map(Some),
+    }
+}
+
+fn match_field(field: &str, expected: &Option<TextPattern>, actual: &Option<String>, result: &mut MatchResult) {
+    if let Some(ref e) = *expected { // not given, so match anything
+        let matches = actual.as_ref().map_or(false, |a| e.matches(a));
+        result.add(field, matches, e.pattern(), actual.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{StubParam, StubRequest};
+    use core::pattern::RequestPattern;
+
+    fn param(name: &str, value: &str) -> StubParam {
+        StubParam { name: name.to_owned(), value: value.to_owned() }
+    }
+
+    #[test]
+    fn test_anchored_regex() {
+        let pattern = RequestPattern::new(&StubRequest {
+            method: Some("GET|HEAD".to_owned()),
+            path: Some("/orders/\\d+".to_owned()),
+            ..Default::default()
+        }).unwrap();
+
+        let request = |method: &str, path: &str| StubRequest {
+            method: Some(method.to_owned()),
+            path: Some(path.to_owned()),
+            ..Default::default()
+        };
+
+        assert!(pattern.matches(&request("GET", "/orders/123")));
+        assert!(pattern.matches(&request("HEAD", "/orders/123")));
+        assert!(!pattern.matches(&request("POST", "/orders/123")));
+        assert!(!pattern.matches(&request("GET", "/orders/123/items")));
+        assert!(!pattern.matches(&request("GET", "/api/orders/123")));
+    }
+
+    #[test]
+    fn test_params_and_headers() {
+        let pattern = RequestPattern::new(&StubRequest {
+            params: vec![param("id", "1"), param("ID", "2"), param("sort", "[a-z]+")],
+            headers: vec![param("X-Trace", ".+")],
+            ..Default::default()
+        }).unwrap();
+
+        let mut request = StubRequest {
+            params: vec![param("Id", "2"), param("id", "1"), param("sort", "name"), param("extra", "x")],
+            headers: vec![param("x-trace", "abc")],
+            ..Default::default()
+        };
+        assert!(pattern.matches(&request));
+
+        request.params.remove(0); // only one of the required values left
+        assert!(!pattern.matches(&request));
+    }
+
+    #[test]
+    fn test_match_result() {
+        let pattern = RequestPattern::new(&StubRequest {
+            method: Some("GET".to_owned()),
+            path: Some("/orders/\\d+".to_owned()),
+            params: vec![param("id", "1")],
+            ..Default::default()
+        }).unwrap();
+
+        let result = pattern.match_request(&StubRequest {
+            method: Some("GET".to_owned()),
+            path: Some("/orders/abc".to_owned()),
+            params: vec![param("id", "2"), param("id", "3")],
+            ..Default::default()
+        });
+
+        assert!(!result.matches);
+        assert_eq!(result.fields.len(), 3);
+        assert_eq!((result.fields[0].field.as_str(), result.fields[0].matches), ("method", true));
+        assert_eq!((result.fields[1].field.as_str(), result.fields[1].matches), ("path", false));
+        assert_eq!(result.fields[1].expected, "/orders/\\d+");
+        assert_eq!(result.fields[1].actual, Some("/orders/abc".to_owned()));
+        assert_eq!((result.fields[2].field.as_str(), result.fields[2].matches), ("param[id]", false));
+        assert_eq!(result.fields[2].actual, Some("2, 3".to_owned()));
+    }
+
+    #[test]
+    fn test_captures() {
+        let pattern = RequestPattern::new(&StubRequest {
+            path: Some("/orders/(\\d+)/items/(?P<item>\\w+)".to_owned()),
+            params: vec![param("Id", "id-(\\d+)")],
+            ..Default::default()
+        }).unwrap();
+
+        let captures = pattern.captures(&StubRequest {
+            path: Some("/orders/123/items/abc".to_owned()),
+            params: vec![param("id", "id-456")],
+            ..Default::default()
+        });
+
+        assert_eq!(captures.path["0"], "/orders/123/items/abc");
+        assert_eq!(captures.path["1"], "123");
+        assert_eq!(captures.path["2"], "abc");
+        assert_eq!(captures.path["item"], "abc");
+        assert_eq!(captures.params["id"]["0"], "id-456");
+        assert_eq!(captures.params["id"]["1"], "456");
+    }
+
+    fn body_request(body: &str, body_type: Option<&str>) -> StubRequest {
+        StubRequest {
+            body: Some(body.as_bytes().to_vec()),
+            body_type: body_type.map(|t| t.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn body_matches(expected: &str, body_type: Option<&str>, actual: &str) -> bool {
+        RequestPattern::new(&body_request(expected, body_type)).unwrap().matches(&body_request(actual, None))
+    }
+
+    #[test]
+    fn test_text_body() {
+        assert!(body_matches("hello", None, "hello"));
+        assert!(body_matches("hello", Some("text"), "hello"));
+        assert!(!body_matches("hello", Some("text"), "hello world"));
+        assert!(!body_matches("hel+o", Some("text"), "hello"));
+        assert!(body_matches("hel+o.*", Some("regex"), "hello world"));
+        assert!(!body_matches("hel+o", Some("regex"), "hello world"));
+    }
+
+    #[test]
+    fn test_json_body() {
+        let expected = r#"{"customer": {"id": 1}, "items": [{"sku": "a"}]}"#;
+        assert!(body_matches(expected, None, r#"{"customer": {"id": 1, "name": "x"}, "items": [{"sku": "a", "qty": 2}], "extra": true}"#));
+        assert!(body_matches(expected, Some("json"), r#"{"items": [{"sku": "a"}], "customer": {"id": 1}}"#));
+        assert!(!body_matches(expected, None, r#"{"customer": {"id": 2}, "items": [{"sku": "a"}]}"#));
+        assert!(!body_matches(expected, None, r#"{"customer": {"id": 1}, "items": [{"sku": "a"}, {"sku": "b"}]}"#));
+        assert!(!body_matches(expected, None, "not json"));
+    }
+
+    #[test]
+    fn test_form_body() {
+        assert!(body_matches("a=1&b=hello+world", Some("form"), "b=hello%20world&a=1"));
+        assert!(!body_matches("a=1&b=2", Some("form"), "a=1"));
+        assert!(!body_matches("a=1", Some("form"), "a=1&b=2"));
+    }
+
+    #[test]
+    fn test_xml_body() {
+        let expected = r#"<order id="1" type="new"><item>a</item></order>"#;
+        assert!(body_matches(expected, Some("xml"), "<?xml version=\"1.0\"?>\n<order type=\"new\" id=\"1\">\n  <item>a</item>\n</order>"));
+        assert!(!body_matches(expected, Some("xml"), r#"<order id="1" type="new"><item>b</item></order>"#));
+        assert!(!body_matches(expected, Some("xml"), "<order"));
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(RequestPattern::new(&body_request("{", Some("json"))).is_err());
+        assert!(RequestPattern::new(&body_request("<a>", Some("xml"))).is_err());
+        assert!(RequestPattern::new(&body_request("a", Some("yaml"))).is_err());
+        assert!(RequestPattern::new(&StubRequest { path: Some("/foo(".to_owned()), ..Default::default() }).is_err());
+        assert!(RequestPattern::new(&StubRequest { headers: vec![param("X", "[")], ..Default::default() }).is_err());
+    }
+}
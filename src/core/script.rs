@@ -0,0 +1,106 @@
+use rhai::{self, Dynamic, Engine, Scope, AST};
+
+use core::{StubRequest, StubResponse};
+
+/// Limit how much work a script can do, as it runs on the server's event loop.
+const MAX_OPERATIONS: u64 = 100_000;
+
+lazy_static! {
+    static ref ENGINE: Engine = {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine
+    };
+}
+
+/// A compiled [Rhai](https://rhai.rs) script from `StubExchange::script`.
+///
+/// The script sees `request` and can change `response` and `delay`, eg:
+///
+/// ```text
+/// response.status = 201;
+/// response.headers.push(#{ name: "Location", value: request.path + "/1" });
+/// response.body = `{"id": "${request.params[0].value}"}`;
+/// delay = 100;
+/// ```
+#[derive(Clone, Debug)]
+pub struct Script {
+    ast: AST
+}
+
+impl Script {
+    pub fn new(source: &str) -> Result<Script, String> {
+        ENGINE.compile(source)
+            .map(|ast| Script { ast: ast })
+            .map_err(|e| format!("Invalid script: {}", e))
+    }
+
+    /// Run the script against copies of the stubbed response and delay, returning the changed copies.
+    pub fn execute(&self,
+                   request: &StubRequest,
+                   response: &StubResponse,
+                   delay: Option<u32>)
+                   -> Result<(StubResponse, Option<u32>), String> {
+        let mut scope = Scope::new();
+        scope.push_constant("request", to_dynamic(request)?);
+        scope.push("response", to_dynamic(response)?);
+        scope.push("delay", to_dynamic(&delay)?);
+
+        ENGINE.run_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|e| format!("Error executing script: {}", e))?;
+
+        let response = scope.get("response").ok_or_else(|| "Script removed 'response'".to_owned())?;
+        let delay = scope.get("delay").ok_or_else(|| "Script removed 'delay'".to_owned())?;
+
+        Ok((from_dynamic(response)?, from_dynamic(delay)?))
+    }
+}
+
+fn to_dynamic<T: ::serde::Serialize>(value: &T) -> Result<Dynamic, String> {
+    rhai::serde::to_dynamic(value).map_err(|e| format!("Error passing value to script: {}", e))
+}
+
+fn from_dynamic<'de, T: ::serde::Deserialize<'de>>(value: &'de Dynamic) -> Result<T, String> {
+    rhai::serde::from_dynamic(value).map_err(|e| format!("Invalid value from script: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{StubParam, StubRequest, StubResponse};
+    use core::script::Script;
+
+    #[test]
+    fn test_execute() {
+        let script = Script::new(r#"
+            response.status = 201;
+            response.headers.push(#{ name: "Location", value: request.path + "/" + request.params[0].value });
+            response.body = `{"id": "${request.params[0].value}"}`;
+            delay = 100;
+        "#).unwrap();
+
+        let request = StubRequest {
+            path: Some("/orders".to_owned()),
+            params: vec![StubParam { name: "id".to_owned(), value: "123".to_owned() }],
+            ..Default::default()
+        };
+        let response = StubResponse { status: 200, ..Default::default() };
+
+        let (response, delay) = script.execute(&request, &response, None).unwrap();
+
+        assert_eq!(response.status, 201);
+        assert_eq!(response.headers, vec![StubParam { name: "Location".to_owned(), value: "/orders/123".to_owned() }]);
+        assert_eq!(response.body, Some(b"{\"id\": \"123\"}".to_vec()));
+        assert_eq!(delay, Some(100));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Script::new("response.status = ").is_err());
+
+        let response = StubResponse { status: 200, ..Default::default() };
+        let endless = Script::new("loop { }").unwrap();
+        assert!(endless.execute(&StubRequest::default(), &response, None).is_err());
+        let invalid = Script::new("response.status = \"ok\";").unwrap();
+        assert!(invalid.execute(&StubRequest::default(), &response, None).is_err());
+    }
+}
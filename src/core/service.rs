@@ -1,3 +1,833 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::sync::oneshot;
+use uuid::Uuid;
+
+use regex;
+
+use core::{validate_status, Fault, Sequence, StubExchange, StubParam, StubProxy, StubRequest, StubResponse};
+use core::body::BODY_TYPE_TEXT;
+use core::pattern::{MatchResult, RequestCaptures, RequestPattern};
+use core::script::Script;
+use core::template::TemplateContext;
+
+/// State of every scenario before any exchange has moved it on.
+pub const SCENARIO_STARTED: &'static str = "started";
+
+#[derive(Debug)]
+pub enum ServiceError {
+    NotFound(String),
+    InvalidPattern(String),
+    InvalidExchange(String)
+}
+
+/// A stubbed exchange along with its compiled request pattern.
+struct StubServiceExchange {
+    id: String,
+    exchange: StubExchange,
+    pattern: RequestPattern,
+    script: Option<Script>,
+    responses: Vec<StubResponse>, // either the single response or the sequence
+    sequence: Sequence,
+    count: usize, // number of times matched
+    expires: Option<Instant>
+}
+
+impl StubServiceExchange {
+    /// Compile the request pattern and assign a new ID (any ID given by the client is replaced).
+    fn new(mut exchange: StubExchange) -> Result<StubServiceExchange, ServiceError> {
+        let pattern = RequestPattern::new(&exchange.request)
+            .map_err(|e| ServiceError::InvalidPattern(format!("Invalid request pattern: {}", e)))?;
+        if let Some(ref delay) = exchange.delay {
+            delay.validate().map_err(ServiceError::InvalidExchange)?;
+        }
+        let script = match exchange.script {
+            Some(ref source) => Some(Script::new(source).map_err(ServiceError::InvalidExchange)?),
+            None => None,
+        };
+        let responses = match (exchange.response.as_ref(), exchange.responses.is_empty()) {
+            (Some(response), true) => vec![response.clone()],
+            (None, false) => exchange.responses.clone(),
+            (Some(_), false) => {
+                return Err(ServiceError::InvalidExchange("Give either 'response' or 'responses', not both".to_owned()))
+            }
+            (None, true) if exchange.proxy.is_some() => vec![],
+            (None, true) => return Err(ServiceError::InvalidExchange("No response given".to_owned())),
+        };
+        if let Some(ref proxy) = exchange.proxy {
+            if !responses.is_empty() {
+                return Err(ServiceError::InvalidExchange("Give either 'proxy' or a response, not both".to_owned()));
+            }
+            proxy.validate().map_err(ServiceError::InvalidExchange)?;
+        }
+        if responses.iter().any(|r| match r.fault { Some(Fault::Drip { bytes: 0, .. }) => true, _ => false }) {
+            return Err(ServiceError::InvalidExchange("Drip fault must send at least 1 byte at a time".to_owned()));
+        }
+        for response in &responses {
+            validate_status(response.status).map_err(ServiceError::InvalidExchange)?;
+            if response.body.is_some() && !response.chunks.is_empty() {
+                return Err(ServiceError::InvalidExchange("Give either 'body' or 'chunks', not both".to_owned()));
+            }
+            for delay in response.chunks.iter().filter_map(|c| c.delay.as_ref()) {
+                delay.validate().map_err(ServiceError::InvalidExchange)?;
+            }
+        }
+        if exchange.scenario.is_none() && (exchange.required_state.is_some() || exchange.new_state.is_some()) {
+            return Err(ServiceError::InvalidExchange("Scenario state given without a 'scenario'".to_owned()));
+        }
+        if exchange.times == Some(0) {
+            return Err(ServiceError::InvalidExchange("'times' must be at least 1".to_owned()));
+        }
+        let id = Uuid::new_v4().simple().to_string();
+        exchange.id = Some(id.clone());
+        exchange.source = None;
+        Ok(StubServiceExchange {
+            id: id,
+            pattern: pattern,
+            script: script,
+            responses: responses,
+            sequence: exchange.sequence.unwrap_or_default(),
+            count: 0,
+            expires: exchange.expires_after_ms.map(|ms| Instant::now() + Duration::from_millis(ms)),
+            exchange: exchange
+        })
+    }
+
+    fn priority(&self) -> i32 {
+        self.exchange.priority.unwrap_or(0)
+    }
+
+    /// Whether adding `other` makes this exchange redundant, ie: `other` would always match in its place.
+    /// Exchanges that are used up or expire sit on top of others rather than replacing them, and exchanges for
+    /// different scenario states are all kept.
+    fn is_replaced_by(&self, other: &StubServiceExchange) -> bool {
+        let temporary = |e: &StubExchange| e.times.is_some() || e.expires_after_ms.is_some();
+        !temporary(&self.exchange) && !temporary(&other.exchange) && self.exchange.request == other.exchange.request &&
+        self.exchange.scenario == other.exchange.scenario &&
+        self.exchange.required_state == other.exchange.required_state
+    }
+
+    /// Whether the exchange has been used `times` times, or has expired.
+    fn is_finished(&self, now: Instant) -> bool {
+        self.exchange.times.map_or(false, |times| self.count >= times as usize) ||
+        self.expires.map_or(false, |expires| now >= expires)
+    }
+
+    /// The next response in the sequence, or `None` if there are none left.
+    fn next_response(&self) -> Option<&StubResponse> {
+        let len = self.responses.len();
+        if self.count < len {
+            return Some(&self.responses[self.count]);
+        }
+        match self.sequence {
+            Sequence::RepeatLast => self.responses.last(),
+            Sequence::Cycle => Some(&self.responses[self.count % len]),
+            Sequence::Stop => None,
+        }
+    }
+
+    fn match_request(&self, request: &StubRequest, scenarios: &HashMap<String, String>) -> MatchResult {
+        let mut result = self.pattern.match_request(request);
+        if self.exchange.proxy.is_none() && self.next_response().is_none() {
+            let expected = format!("at most {} requests", self.responses.len());
+            result.add_condition("responses", false, &expected, Some(self.count.to_string()));
+        }
+        if let (&Some(ref scenario), &Some(ref required)) = (&self.exchange.scenario, &self.exchange.required_state) {
+            let state = scenario_state(scenarios, scenario);
+            let field = format!("scenario[{}]", scenario);
+            result.add_condition(&field, state == required, required, Some(state.to_owned()));
+        }
+        result
+    }
+
+    /// The scenario and its new state, if matching this exchange moves a scenario on.
+    fn transition(&self) -> Option<(String, String)> {
+        match (&self.exchange.scenario, &self.exchange.new_state) {
+            (&Some(ref scenario), &Some(ref state)) => Some((scenario.clone(), state.clone())),
+            _ => None,
+        }
+    }
+
+    /// Where to forward the request (and delay before sending the response), for an exchange with a `proxy`.
+    fn forward(&mut self, request: &StubRequest) -> (StubProxy, Option<u32>) {
+        self.count += 1;
+        let proxy = {
+            let proxy = self.exchange.proxy.as_ref().expect("Stub is not a proxy");
+            let captures = self.pattern.captures(request);
+            TemplateContext::new(request, &captures).render_proxy(proxy)
+        };
+        (proxy, self.exchange.delay.as_ref().map(|d| d.sample()))
+    }
+
+    /// The response to send (and delay before sending it), after rendering templates and running any script.
+    /// Moves on to the next response in the sequence.
+    fn respond(&mut self, request: &StubRequest) -> (StubResponse, Option<u32>) {
+        let response = {
+            let next = self.next_response().expect("Stub has no responses left");
+            if next.template {
+                let captures = self.pattern.captures(request);
+                TemplateContext::new(request, &captures).render_response(next)
+            } else {
+                next.clone()
+            }
+        };
+        self.count += 1;
+        let delay = self.exchange.delay.as_ref().map(|d| d.sample());
+        match self.script {
+            None => (response, delay),
+            Some(ref script) => {
+                match script.execute(request, &response, delay) { // works on copies of response and delay
+                    Ok(result) => result,
+                    Err(message) => {
+                        error!("Error running script for {}: {}", self.id, message);
+                        (StubResponse {
+                            status: 500,
+                            body: Some(message.into_bytes()),
+                            ..Default::default()
+                        }, None)
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn scenario_state<'a>(scenarios: &'a HashMap<String, String>, name: &str) -> &'a str {
+    scenarios.get(name).map_or(SCENARIO_STARTED, |s| s)
+}
+
+/// The current state of a scenario.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Scenario {
+    pub name: String,
+    pub state: String
+}
+
+/// The result of testing a request against one stubbed exchange.
+#[derive(Clone, Debug, Serialize)]
+pub struct MatchAttempt {
+    pub id: String, // of the stubbed exchange
+    pub request: StubRequest, // the stubbed request pattern
+    pub result: MatchResult
+}
+
+/// A `MatchAttempt` as kept in the journal, without a copy of the stubbed request pattern (that is at
+/// `/_control/responses/{id}`, while the exchange is still around) to keep the journal small.
+#[derive(Clone, Debug, Serialize)]
+pub struct JournalAttempt {
+    pub id: String, // of the stubbed exchange
+    pub result: MatchResult
+}
+
+pub struct StubServiceResult {
+    pub attempts: Vec<MatchAttempt>,
+    pub response: Option<StubResponse>,
+    pub proxy: Option<StubProxy>, // if matched an exchange that forwards the request
+    pub delay: Option<u32>
+}
+
+impl StubServiceResult {
+    pub fn matched(&self) -> bool {
+        self.response.is_some() || self.proxy.is_some()
+    }
+}
+
+/// A request received by the stub matcher, as recorded in the journal.
+#[derive(Clone, Debug, Serialize)]
+pub struct JournalEntry {
+    pub timestamp: DateTime<Utc>,
+    pub matched: bool,
+    pub request: StubRequest,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<JournalAttempt> // only kept if nothing matched
+}
+
+#[derive(Default)]
+struct StubServiceState {
+    requests: Vec<JournalEntry>, // most recent first
+    responses: Vec<StubServiceExchange>,
+    waiters: Vec<oneshot::Sender<()>>,
+    scenarios: HashMap<String, String>, // only those moved on from `SCENARIO_STARTED`
+    proxy: Option<StubProxy> // where to forward requests that don't match
+}
+
+impl StubServiceState {
+    /// Ensure highest priority matched first, then most recent.
+    fn insert(&mut self, exchange: StubServiceExchange) {
+        let priority = exchange.priority();
+        let index = self.responses.iter().position(|r| r.priority() <= priority).unwrap_or(self.responses.len());
+        self.responses.insert(index, exchange);
+    }
+
+    /// Drop exchanges that have been used up or have expired.
+    fn remove_finished(&mut self) {
+        let now = Instant::now();
+        self.responses.retain(|r| {
+            let finished = r.is_finished(now);
+            if finished {
+                debug!("Removing finished response: {}", r.id);
+            }
+            !finished
+        });
+    }
+}
+
+/// Thread-safe store of stubbed exchanges, shared between all connections of a `Server`.
+#[derive(Default)]
+pub struct StubService {
+    state: Mutex<StubServiceState>
+}
+
+impl StubService {
+    pub fn new() -> StubService {
+        Default::default()
+    }
+
+    /// Returns the ID assigned to the new exchange.
+    pub fn add_response(&self, exchange: StubExchange) -> Result<String, ServiceError> {
+        trace!("Adding stubbed exchange: {:?}", exchange);
+        let internal = StubServiceExchange::new(exchange)?; // compile before taking the lock
+        let id = internal.id.clone();
+        let mut state = self.state.lock().unwrap();
+        // remove existing stubbed request (ie, will never match anymore)
+        state.responses.retain(|r| !r.is_replaced_by(&internal));
+        state.insert(internal);
+        Ok(id)
+    }
+
+    /// Replace the exchanges from `source` (eg, a file) with new ones, leaving all others alone.
+    /// Nothing is changed if any of the new exchanges are invalid.
+    pub fn replace_responses_from(&self, source: &str, exchanges: Vec<StubExchange>) -> Result<usize, ServiceError> {
+        let mut internals = exchanges.into_iter().map(StubServiceExchange::new).collect::<Result<Vec<_>, _>>()?;
+        let count = internals.len();
+        let mut state = self.state.lock().unwrap();
+        trace!("Replacing responses from: {}", source);
+        state.responses.retain(|r| r.exchange.source.as_ref().map_or(true, |s| s != source));
+        for mut internal in internals.drain(..) {
+            internal.exchange.source = Some(source.to_owned());
+            state.insert(internal);
+        }
+        Ok(count)
+    }
+
+    pub fn find_match(&self, request: &StubRequest) -> StubServiceResult {
+        let mut state = self.state.lock().unwrap();
+        trace!("Got request: {:?}", request);
+        state.remove_finished();
+
+        let mut attempts = vec![];
+        let mut found = None;
+        for (index, response) in state.responses.iter().enumerate() {
+            let result = response.match_request(request, &state.scenarios);
+            let matches = result.matches;
+            attempts.push(MatchAttempt {
+                id: response.id.clone(),
+                request: response.exchange.request.clone(),
+                result: result
+            });
+            if matches {
+                found = Some(index);
+                break;
+            }
+        }
+
+        let result = match found {
+            Some(index) => {
+                info!("Matched: {}", request.path.as_ref().map_or("", |p| p));
+                let (response, proxy, delay) = if state.responses[index].exchange.proxy.is_some() {
+                    let (proxy, delay) = state.responses[index].forward(request);
+                    (None, Some(proxy), delay)
+                } else {
+                    let (response, delay) = state.responses[index].respond(request);
+                    (Some(response), None, delay)
+                };
+                if let Some((scenario, new_state)) = state.responses[index].transition() {
+                    debug!("Scenario {} moved to state: {}", scenario, new_state);
+                    state.scenarios.insert(scenario, new_state);
+                }
+                state.remove_finished();
+                StubServiceResult {
+                    attempts: attempts,
+                    response: response,
+                    proxy: proxy,
+                    delay: delay
+                }
+            }
+            None => {
+                info!("Didn't match: {}", request.path.as_ref().map_or("", |p| p));
+                StubServiceResult {
+                    attempts: attempts,
+                    response: None,
+                    proxy: None,
+                    delay: None
+                }
+            }
+        };
+
+        state.requests.insert(0, JournalEntry {
+            timestamp: Utc::now(),
+            matched: result.matched(),
+            request: request.clone(),
+            attempts: if result.matched() {
+                vec![]
+            } else {
+                result.attempts.iter().map(|a| JournalAttempt { id: a.id.clone(), result: a.result.clone() }).collect()
+            }
+        });
+
+        // inform anyone waiting that a new request has come in
+        for waiter in state.waiters.drain(..) {
+            let _ = waiter.send(()); // may have given up waiting already
+        }
+
+        result
+    }
+
+    pub fn get_responses(&self) -> Vec<StubExchange> {
+        let mut state = self.state.lock().unwrap();
+        state.remove_finished();
+        state.responses.iter().map(|r| r.exchange.clone()).collect()
+    }
+
+    pub fn get_response(&self, id: &str) -> Result<StubExchange, ServiceError> {
+        let mut state = self.state.lock().unwrap();
+        state.remove_finished();
+        match state.responses.iter().find(|r| r.id == id) {
+            Some(r) => Ok(r.exchange.clone()),
+            None => Err(ServiceError::NotFound(format!("Response does not exist: {}", id))),
+        }
+    }
+
+    pub fn delete_response(&self, id: &str) -> Result<(), ServiceError> {
+        let mut state = self.state.lock().unwrap();
+        trace!("Deleting response: {}", id);
+        match state.responses.iter().position(|r| r.id == id) {
+            Some(index) => {
+                state.responses.remove(index);
+                Ok(())
+            }
+            None => Err(ServiceError::NotFound(format!("Response does not exist: {}", id))),
+        }
+    }
+
+    /// Save a request forwarded by a proxy, and the response it got, as an exchange matching that exact request.
+    pub fn record(&self, request: &StubRequest, response: StubResponse) -> Result<StubExchange, ServiceError> {
+        let exchange = StubExchange {
+            request: exact_request(request),
+            response: Some(response),
+            ..Default::default()
+        };
+        let id = self.add_response(exchange)?;
+        self.get_response(&id)
+    }
+
+    /// Delete every exchange whose request pattern matches `request`, returning how many were deleted.
+    pub fn delete_matching_responses(&self, request: &StubRequest) -> usize {
+        let mut state = self.state.lock().unwrap();
+        trace!("Deleting responses matching: {:?}", request);
+        let before = state.responses.len();
+        state.responses.retain(|r| !r.pattern.matches(request));
+        before - state.responses.len()
+    }
+
+    pub fn delete_responses(&self) {
+        let mut state = self.state.lock().unwrap();
+        trace!("Deleting all responses");
+        state.responses.clear();
+    }
+
+    pub fn get_proxy(&self) -> Option<StubProxy> {
+        let state = self.state.lock().unwrap();
+        state.proxy.clone()
+    }
+
+    /// The proxy to forward an unmatched request to, with any templates in it rendered.
+    pub fn get_proxy_for(&self, request: &StubRequest) -> Option<StubProxy> {
+        self.get_proxy().map(|p| TemplateContext::new(request, &RequestCaptures::default()).render_proxy(&p))
+    }
+
+    /// Forward requests that don't match any exchange (or stop, if `None`).
+    pub fn set_proxy(&self, proxy: Option<StubProxy>) -> Result<(), ServiceError> {
+        if let Some(ref p) = proxy {
+            p.validate().map_err(ServiceError::InvalidExchange)?;
+        }
+        let mut state = self.state.lock().unwrap();
+        trace!("Setting proxy: {:?}", proxy);
+        state.proxy = proxy;
+        Ok(())
+    }
+
+    /// Scenarios used by any stubbed exchange or given a state directly, sorted by name.
+    pub fn get_scenarios(&self) -> Vec<Scenario> {
+        let state = self.state.lock().unwrap();
+        let mut scenarios = BTreeMap::new();
+        for name in state.responses.iter().filter_map(|r| r.exchange.scenario.as_ref()).chain(state.scenarios.keys()) {
+            scenarios.insert(name.clone(), scenario_state(&state.scenarios, name).to_owned());
+        }
+        scenarios.into_iter().map(|(name, state)| Scenario { name: name, state: state }).collect()
+    }
+
+    pub fn set_scenario_state(&self, name: &str, new_state: &str) {
+        let mut state = self.state.lock().unwrap();
+        trace!("Setting scenario {} to state: {}", name, new_state);
+        state.scenarios.insert(name.to_owned(), new_state.to_owned());
+    }
+
+    pub fn reset_scenario(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        trace!("Resetting scenario: {}", name);
+        state.scenarios.remove(name);
+    }
+
+    pub fn reset_scenarios(&self) {
+        let mut state = self.state.lock().unwrap();
+        trace!("Resetting all scenarios");
+        state.scenarios.clear();
+    }
+
+    pub fn find_requests(&self, filter: &RequestPattern) -> Vec<JournalEntry> {
+        let state = self.state.lock().unwrap();
+        state.requests.iter().filter(|r| filter.matches(&r.request)).cloned().collect()
+    }
+
+    /// Completes when the next request is recorded in the journal.
+    pub fn notify_on_request(&self) -> oneshot::Receiver<()> {
+        let mut state = self.state.lock().unwrap();
+        let (sender, receiver) = oneshot::channel();
+        state.waiters.retain(|w| !w.is_canceled()); // drop waiters that have timed out
+        state.waiters.push(sender);
+        receiver
+    }
+
+    pub fn get_request(&self, index: usize) -> Result<JournalEntry, ServiceError> {
+        let state = self.state.lock().unwrap();
+        match state.requests.get(index) {
+            Some(r) => Ok(r.clone()),
+            None => Err(ServiceError::NotFound(format!("Request does not exist: {}", index))),
+        }
+    }
+
+    pub fn delete_request(&self, index: usize) -> Result<(), ServiceError> {
+        let mut state = self.state.lock().unwrap();
+        trace!("Deleting request: {}", index);
+        if index < state.requests.len() {
+            state.requests.remove(index);
+            Ok(())
+        } else {
+            Err(ServiceError::NotFound(format!("Request does not exist: {}", index)))
+        }
+    }
+
+    pub fn delete_requests(&self) {
+        let mut state = self.state.lock().unwrap();
+        trace!("Deleting all requests");
+        state.requests.clear();
+    }
+}
+
+/// A pattern matching only the given request (ignoring its headers).
+fn exact_request(request: &StubRequest) -> StubRequest {
+    let exact = |value: &String| regex::escape(value);
+    StubRequest {
+        method: request.method.as_ref().map(&exact),
+        path: request.path.as_ref().map(&exact),
+        params: request.params
+            .iter()
+            .map(|p| StubParam { name: p.name.clone(), value: exact(&p.value) })
+            .collect(),
+        headers: vec![],
+        body: request.body.clone(),
+        body_type: request.body.as_ref().map(|_| BODY_TYPE_TEXT.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{Sequence, StubChunk, StubExchange, StubParam, StubProxy, StubRequest, StubResponse};
+    use core::service::{JournalEntry, Scenario, StubService, SCENARIO_STARTED};
+    use core::pattern::RequestPattern;
+    use futures::Future;
+    use std::thread;
+    use std::time::Duration;
+
+    fn exchange(path: &str, status: u16) -> StubExchange {
+        StubExchange {
+            request: StubRequest { path: Some(path.to_owned()), ..Default::default() },
+            response: Some(StubResponse { status: status, ..Default::default() }),
+            ..Default::default()
+        }
+    }
+
+    fn scenario(path: &str, status: u16, required: &str, new_state: Option<&str>) -> StubExchange {
+        StubExchange {
+            scenario: Some("cart".to_owned()),
+            required_state: Some(required.to_owned()),
+            new_state: new_state.map(|s| s.to_owned()),
+            ..exchange(path, status)
+        }
+    }
+
+    fn sequence(path: &str, statuses: &[u16], sequence: Sequence) -> StubExchange {
+        StubExchange {
+            response: None,
+            responses: statuses.iter().map(|&s| StubResponse { status: s, ..Default::default() }).collect(),
+            sequence: Some(sequence),
+            ..exchange(path, 0)
+        }
+    }
+
+    fn request(path: &str) -> StubRequest {
+        StubRequest { method: Some("GET".to_owned()), path: Some(path.to_owned()), ..Default::default() }
+    }
+
+    fn all_requests(service: &StubService) -> Vec<JournalEntry> {
+        service.find_requests(&RequestPattern::new(&StubRequest::default()).unwrap())
+    }
+
+    #[test]
+    fn test_most_recent_first() {
+        let service = StubService::new();
+        service.add_response(exchange("/foo", 200)).unwrap();
+        service.add_response(exchange("/bar", 201)).unwrap();
+
+        let responses = service.get_responses();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].response.as_ref().unwrap().status, 201);
+        assert_eq!(service.find_match(&request("/foo")).response.unwrap().status, 200);
+        assert!(!service.find_match(&request("/baz")).matched());
+    }
+
+    #[test]
+    fn test_priority() {
+        let service = StubService::new();
+        service.add_response(StubExchange { priority: Some(-1), ..exchange(".*", 503) }).unwrap();
+        service.add_response(StubExchange { priority: Some(10), ..exchange("/fo+", 201) }).unwrap();
+        service.add_response(exchange("/foo", 200)).unwrap();
+        service.add_response(StubExchange { priority: Some(-1), ..exchange("/.*", 500) }).unwrap();
+
+        assert_eq!(service.find_match(&request("/foo")).response.unwrap().status, 201);
+        assert_eq!(service.find_match(&request("/bar")).response.unwrap().status, 500); // most recent of equal priority
+        let statuses: Vec<u16> = service.get_responses().iter().map(|r| r.response.as_ref().unwrap().status).collect();
+        assert_eq!(statuses, vec![201, 200, 500, 503]);
+    }
+
+    #[test]
+    fn test_replaces_identical_request() {
+        let service = StubService::new();
+        service.add_response(exchange("/foo", 200)).unwrap();
+        service.add_response(exchange("/foo", 500)).unwrap();
+
+        assert_eq!(service.get_responses().len(), 1);
+        assert_eq!(service.find_match(&request("/foo")).response.unwrap().status, 500);
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let service = StubService::new();
+        assert!(service.add_response(exchange("/foo(", 200)).is_err());
+        assert!(service.get_responses().is_empty());
+    }
+
+    #[test]
+    fn test_response_sequence() {
+        let service = StubService::new();
+        let statuses = |service: &StubService, path: &str| -> Vec<Option<u16>> {
+            (0..5).map(|_| service.find_match(&request(path)).response.map(|r| r.status)).collect()
+        };
+
+        service.add_response(sequence("/last", &[500, 500, 200], Sequence::RepeatLast)).unwrap();
+        assert_eq!(statuses(&service, "/last"), vec![Some(500), Some(500), Some(200), Some(200), Some(200)]);
+
+        service.add_response(sequence("/cycle", &[500, 200], Sequence::Cycle)).unwrap();
+        assert_eq!(statuses(&service, "/cycle"), vec![Some(500), Some(200), Some(500), Some(200), Some(500)]);
+
+        service.add_response(sequence("/stop", &[500, 200], Sequence::Stop)).unwrap();
+        assert_eq!(statuses(&service, "/stop"), vec![Some(500), Some(200), None, None, None]);
+        let attempt = &all_requests(&service)[0].attempts[0];
+        assert!(!attempt.result.matches);
+        assert!(attempt.result.fields.iter().any(|f| f.field == "responses" && !f.matches));
+    }
+
+    #[test]
+    fn test_invalid_responses() {
+        let service = StubService::new();
+        assert!(service.add_response(sequence("/foo", &[], Sequence::Cycle)).is_err());
+        let mut chunked = exchange("/foo", 200);
+        chunked.response = Some(StubResponse {
+            status: 200,
+            body: Some(b"body".to_vec()),
+            chunks: vec![StubChunk { body: Some(b"chunk".to_vec()), delay: None }],
+            ..Default::default()
+        });
+        assert!(service.add_response(chunked).is_err());
+        let both = StubExchange { response: Some(Default::default()), ..sequence("/foo", &[200], Sequence::Cycle) };
+        assert!(service.add_response(both).is_err());
+        assert!(service.add_response(exchange("/foo", 1000)).is_err());
+        assert!(service.add_response(sequence("/foo", &[200, 99], Sequence::Cycle)).is_err());
+        let proxy = StubProxy { url: "http://x".to_owned(), path: None, headers: vec![], status: Some(0), record: false, record_dir: None };
+        assert!(service.add_response(StubExchange { response: None, proxy: Some(proxy), ..exchange("/foo", 200) }).is_err());
+    }
+
+    #[test]
+    fn test_times() {
+        let service = StubService::new();
+        service.add_response(exchange("/foo", 200)).unwrap();
+        service.add_response(StubExchange { times: Some(2), ..exchange("/foo", 500) }).unwrap();
+        assert!(service.add_response(StubExchange { times: Some(0), ..exchange("/bar", 500) }).is_err());
+
+        assert_eq!(service.find_match(&request("/foo")).response.unwrap().status, 500);
+        assert_eq!(service.find_match(&request("/foo")).response.unwrap().status, 500);
+        assert_eq!(service.get_responses().len(), 1); // used up, so dropped
+        assert_eq!(service.find_match(&request("/foo")).response.unwrap().status, 200);
+    }
+
+    #[test]
+    fn test_expires() {
+        let service = StubService::new();
+        let id = service.add_response(StubExchange { expires_after_ms: Some(50), ..exchange("/foo", 200) }).unwrap();
+        assert!(service.find_match(&request("/foo")).matched());
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(!service.find_match(&request("/foo")).matched());
+        assert!(service.get_response(&id).is_err());
+    }
+
+    #[test]
+    fn test_scenarios() {
+        let service = StubService::new();
+        service.add_response(scenario("/cart", 204, "started", None)).unwrap();
+        service.add_response(scenario("/cart/items", 201, "started", Some("has-items"))).unwrap();
+        service.add_response(scenario("/cart", 200, "has-items", None)).unwrap();
+        assert_eq!(service.get_responses().len(), 3); // same request, but different state
+
+        let scenarios = service.get_scenarios();
+        assert_eq!(scenarios, vec![Scenario { name: "cart".to_owned(), state: SCENARIO_STARTED.to_owned() }]);
+
+        assert_eq!(service.find_match(&request("/cart")).response.unwrap().status, 204);
+        assert_eq!(service.find_match(&request("/cart/items")).response.unwrap().status, 201);
+        assert_eq!(service.get_scenarios()[0].state, "has-items");
+        assert_eq!(service.find_match(&request("/cart")).response.unwrap().status, 200);
+        assert!(!service.find_match(&request("/cart/items")).matched());
+        let attempt = &all_requests(&service)[0].attempts[1];
+        assert!(attempt.result.fields.iter().any(|f| f.field == "scenario[cart]" && !f.matches));
+
+        service.reset_scenarios();
+        assert_eq!(service.find_match(&request("/cart")).response.unwrap().status, 204);
+        service.set_scenario_state("cart", "has-items");
+        assert_eq!(service.find_match(&request("/cart")).response.unwrap().status, 200);
+        service.reset_scenario("cart");
+        assert_eq!(service.get_scenarios()[0].state, SCENARIO_STARTED);
+
+        let invalid = StubExchange { required_state: Some("started".to_owned()), ..exchange("/foo", 200) };
+        assert!(service.add_response(invalid).is_err());
+    }
+
+    #[test]
+    fn test_delete() {
+        let service = StubService::new();
+        let foo = service.add_response(exchange("/foo", 200)).unwrap();
+        let bar = service.add_response(exchange("/bar", 200)).unwrap();
+        assert!(foo != bar);
+
+        assert_eq!(service.get_response(&foo).unwrap().id, Some(foo.clone()));
+        assert!(service.delete_response("unknown").is_err());
+        assert!(service.delete_response(&bar).is_ok());
+        assert!(service.get_response(&bar).is_err());
+        assert!(!service.find_match(&request("/bar")).matched());
+        assert!(service.find_match(&request("/foo")).matched());
+
+        service.delete_responses();
+        assert!(service.get_responses().is_empty());
+    }
+
+    #[test]
+    fn test_record() {
+        let service = StubService::new();
+        let recorded = StubRequest { params: vec![StubParam { name: "q".to_owned(), value: "a+b".to_owned() }],
+                                     ..request("/orders/(1)") };
+        let exchange = service.record(&recorded, StubResponse { status: 200, ..Default::default() }).unwrap();
+        assert!(exchange.id.is_some());
+
+        assert!(service.find_match(&recorded).matched());
+        assert!(!service.find_match(&request("/orders/(1)")).matched());
+        assert!(!service.find_match(&StubRequest { path: Some("/orders/1".to_owned()), ..recorded.clone() }).matched());
+
+        assert!(service.set_proxy(Some(StubProxy { url: "ftp://x".to_owned(), path: None, headers: vec![], status: None, record: false, record_dir: None })).is_err());
+        let https = StubProxy { url: "https://x".to_owned(), path: None, headers: vec![], status: None, record: false, record_dir: None };
+        assert!(format!("{:?}", service.set_proxy(Some(https)).unwrap_err()).contains("https is not supported"));
+    }
+
+    #[test]
+    fn test_replace_responses_from() {
+        let service = StubService::new();
+        service.add_response(exchange("/api", 200)).unwrap();
+        assert_eq!(service.replace_responses_from("a.json", vec![exchange("/a", 200), exchange("/b", 200)]).unwrap(), 2);
+        assert_eq!(service.get_responses()[0].source, Some("a.json".to_owned()));
+
+        assert!(service.replace_responses_from("a.json", vec![exchange("/c(", 200)]).is_err());
+        assert_eq!(service.get_responses().len(), 3); // unchanged
+
+        service.replace_responses_from("a.json", vec![exchange("/c", 200)]).unwrap();
+        assert!(service.find_match(&request("/c")).matched());
+        assert!(!service.find_match(&request("/a")).matched());
+
+        service.replace_responses_from("a.json", vec![]).unwrap();
+        let responses = service.get_responses();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].source, None);
+    }
+
+    #[test]
+    fn test_delete_matching() {
+        let service = StubService::new();
+        service.add_response(exchange("/foo", 200)).unwrap();
+        service.add_response(exchange("/fo+", 200)).unwrap();
+        service.add_response(exchange("/bar", 200)).unwrap();
+
+        assert_eq!(service.delete_matching_responses(&request("/foo")), 2);
+        assert_eq!(service.delete_matching_responses(&request("/foo")), 0);
+        assert_eq!(service.get_responses().len(), 1);
+        assert!(service.find_match(&request("/bar")).matched());
+    }
+
+    #[test]
+    fn test_journal() {
+        let service = StubService::new();
+        service.add_response(exchange("/foo", 200)).unwrap();
+        service.find_match(&request("/foo"));
+        service.find_match(&request("/bar"));
+
+        let requests = all_requests(&service);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].request.path, Some("/bar".to_owned())); // most recent first
+        assert!(!requests[0].matched);
+        assert_eq!(requests[0].attempts.len(), 1);
+        assert!(!requests[0].attempts[0].result.matches);
+        assert!(requests[1].matched);
+        assert!(requests[1].attempts.is_empty());
+
+        assert!(service.delete_request(0).is_ok());
+        assert_eq!(service.get_request(0).unwrap().request.path, Some("/foo".to_owned()));
+        assert!(service.get_request(1).is_err());
+
+        service.delete_requests();
+        assert!(all_requests(&service).is_empty());
+    }
+
+    #[test]
+    fn test_notify_on_request() {
+        let service = StubService::new();
+        let notified = service.notify_on_request();
+        service.find_match(&request("/foo"));
+
+        assert!(notified.wait().is_ok());
+        assert_eq!(service.find_requests(&RequestPattern::new(&request("/foo")).unwrap()).len(), 1);
+        assert!(service.find_requests(&RequestPattern::new(&request("/bar")).unwrap()).is_empty());
+    }
+}
 /*
 
 
@@ -0,0 +1,214 @@
+use std::borrow::Cow;
+use std::fmt::Write;
+
+use chrono::Utc;
+use regex::{Captures, Regex};
+use serde_json::{self, Value};
+use uuid::Uuid;
+
+use core::{StubChunk, StubMessage, StubParam, StubProxy, StubRequest, StubResponse};
+use core::pattern::RequestCaptures;
+
+lazy_static! {
+    static ref EXPRESSION: Regex = Regex::new(r"\{\{\s*(.+?)\s*\}\}").unwrap();
+}
+
+/// Values available to `{{...}}` expressions in a response template:
+///
+/// * `{{method}}`, `{{path}}`, `{{body}}` - from the request
+/// * `{{path.1}}`, `{{path.name}}` - capture group from the stubbed path regex
+/// * `{{param.id}}`, `{{param.id.1}}` - param value, or capture group from the stubbed param regex
+/// * `{{header.X-Correlation-Id}}` - header value
+/// * `{{body.$.customer.id}}` - value from a JSON body, eg: `$.items[0].sku`
+/// * `{{now}}`, `{{now iso8601}}`, `{{now epoch}}`, `{{now epoch_ms}}`, `{{now %Y-%m-%d}}` - current time (UTC)
+/// * `{{uuid}}` - a random UUID
+///
+/// Unknown expressions are left as they are, and missing values are replaced with nothing.
+pub struct TemplateContext<'a> {
+    request: &'a StubRequest,
+    captures: &'a RequestCaptures
+}
+
+impl<'a> TemplateContext<'a> {
+    pub fn new(request: &'a StubRequest, captures: &'a RequestCaptures) -> TemplateContext<'a> {
+        TemplateContext {
+            request: request,
+            captures: captures
+        }
+    }
+
+    /// Render the body (or chunks) and header values of a response.
+    pub fn render_response(&self, response: &StubResponse) -> StubResponse {
+        StubResponse {
+            headers: self.render_params(&response.headers),
+            body: response.body.as_ref().map(|b| self.render_body(b)),
+            chunks: response.chunks
+                .iter()
+                .map(|c| StubChunk { body: c.body.as_ref().map(|b| self.render_body(b)), ..c.clone() })
+                .collect(),
+            ..response.clone()
+        }
+    }
+
+    /// Render the path and added header values of a proxy.
+    pub fn render_proxy(&self, proxy: &StubProxy) -> StubProxy {
+        StubProxy {
+            path: proxy.path.as_ref().map(|p| self.render(p)),
+            headers: self.render_params(&proxy.headers),
+            ..proxy.clone()
+        }
+    }
+
+    fn render_params(&self, params: &[StubParam]) -> Vec<StubParam> {
+        params.iter().map(|p| StubParam { name: p.name.clone(), value: self.render(&p.value) }).collect()
+    }
+
+    fn render_body(&self, body: &[u8]) -> Vec<u8> {
+        self.render(&String::from_utf8_lossy(body)).into_bytes()
+    }
+
+    pub fn render(&self, template: &str) -> String {
+        EXPRESSION.replace_all(template, |caps: &Captures| {
+            self.evaluate(&caps[1]).unwrap_or_else(|| caps[0].to_owned())
+        }).into_owned()
+    }
+
+    /// Returns `None` for an unknown expression.
+    fn evaluate(&self, expression: &str) -> Option<String> {
+        let (name, arg) = match expression.find(char::is_whitespace) {
+            Some(i) => (&expression[..i], Some(expression[i..].trim())),
+            None => (expression, None),
+        };
+        let (kind, key) = match name.find('.') {
+            Some(i) => (&name[..i], Some(&name[i + 1..])),
+            None => (name, None),
+        };
+
+        let request = self.request;
+        match (kind, key) {
+            ("method", None) => Some(request.method.clone().unwrap_or_default()),
+            ("path", None) => Some(request.path.clone().unwrap_or_default()),
+            ("path", Some(group)) => Some(self.captures.path.get(group).cloned().unwrap_or_default()),
+            ("param", Some(key)) => Some(self.param(key)),
+            ("header", Some(key)) => Some(request.get_header(key).unwrap_or("").to_owned()),
+            ("body", None) => Some(self.body().into_owned()),
+            ("body", Some(path)) => Some(self.json_path(path)),
+            ("now", None) => now(arg.unwrap_or("iso8601")),
+            ("uuid", None) => Some(Uuid::new_v4().hyphenated().to_string()),
+            _ => None,
+        }
+    }
+
+    /// `name` or `name.group`, where group is a capture group of the stubbed param regex.
+    fn param(&self, key: &str) -> String {
+        let captures = &self.captures.params;
+        if let Some(groups) = captures.get(&key.to_lowercase()) {
+            return groups.get("0").cloned().unwrap_or_default();
+        }
+        if let Some(i) = key.rfind('.') {
+            if let Some(groups) = captures.get(&key[..i].to_lowercase()) {
+                return groups.get(&key[i + 1..]).cloned().unwrap_or_default();
+            }
+        }
+        self.request.get_param(key).unwrap_or("").to_owned()
+    }
+
+    fn body(&self) -> Cow<'_, str> {
+        match self.request.body {
+            Some(ref body) => String::from_utf8_lossy(body),
+            None => "".into(),
+        }
+    }
+
+    fn json_path(&self, path: &str) -> String {
+        let json = match serde_json::from_str::<Value>(&self.body()) {
+            Ok(json) => json,
+            Err(_) => return String::new(),
+        };
+        match select(&json, path) {
+            Some(&Value::String(ref s)) => s.clone(),
+            Some(&Value::Null) | None => String::new(),
+            Some(value) => value.to_string(),
+        }
+    }
+}
+
+/// Select a value with a simple JSON path, eg: `$.items[0].sku`
+fn select<'v>(json: &'v Value, path: &str) -> Option<&'v Value> {
+    let path = path.trim_start_matches('$');
+    let mut current = json;
+    for segment in path.split('.').filter(|s| !s.is_empty()) {
+        let mut parts = segment.split('[');
+        let field = parts.next().unwrap_or("");
+        if !field.is_empty() {
+            current = current.get(field)?;
+        }
+        for index in parts {
+            let index = index.trim_end_matches(']').parse::<usize>().ok()?;
+            current = current.get(index)?;
+        }
+    }
+    Some(current)
+}
+
+/// The current time, or `None` if `format` isn't a valid strftime format.
+fn now(format: &str) -> Option<String> {
+    let now = Utc::now();
+    match format {
+        "iso8601" => Some(now.to_rfc3339()),
+        "epoch" => Some(now.timestamp().to_string()),
+        "epoch_ms" => Some((now.timestamp() * 1000 + now.timestamp_subsec_millis() as i64).to_string()),
+        format => {
+            let mut formatted = String::new();
+            match write!(formatted, "{}", now.format(format)) {
+                Ok(_) => Some(formatted),
+                Err(_) => None, // to_string() would panic
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{StubParam, StubRequest};
+    use core::pattern::RequestPattern;
+    use core::template::TemplateContext;
+
+    fn param(name: &str, value: &str) -> StubParam {
+        StubParam { name: name.to_owned(), value: value.to_owned() }
+    }
+
+    #[test]
+    fn test_render() {
+        let pattern = RequestPattern::new(&StubRequest {
+            path: Some("/orders/(\\d+)".to_owned()),
+            params: vec![param("ref", "REF-(.*)")],
+            ..Default::default()
+        }).unwrap();
+
+        let request = StubRequest {
+            method: Some("PUT".to_owned()),
+            path: Some("/orders/123".to_owned()),
+            params: vec![param("ref", "REF-abc"), param("other", "x")],
+            headers: vec![param("X-Correlation-Id", "c-1")],
+            body: Some(br#"{"customer": {"id": 7}, "items": [{"sku": "a"}]}"#.to_vec()),
+            body_type: None
+        };
+
+        let captures = pattern.captures(&request);
+        let context = TemplateContext::new(&request, &captures);
+
+        assert_eq!(context.render("{{method}} {{path}} {{ path.1 }}"), "PUT /orders/123 123");
+        assert_eq!(context.render("{{param.ref}} {{param.ref.1}} {{param.other}} {{param.missing}}."),
+                   "REF-abc abc x .");
+        assert_eq!(context.render("{{header.x-correlation-id}}"), "c-1");
+        assert_eq!(context.render("{{body.$.customer.id}}/{{body.$.items[0].sku}}/{{body.$.nope}}"), "7/a/");
+        assert_eq!(context.render("{{unknown}} {{ }}"), "{{unknown}} {{ }}");
+
+        assert_eq!(context.render("{{uuid}}").len(), 36);
+        assert_eq!(context.render("{{now %Y}}").len(), 4);
+        assert!(context.render("{{now}}").contains('T'));
+        assert!(context.render("{{now epoch}}").parse::<i64>().is_ok());
+        assert_eq!(context.render("{{now %Q}}"), "{{now %Q}}");
+    }
+}
@@ -0,0 +1,122 @@
+use hyper::{Headers, Method, StatusCode, Uri};
+use hyper::server::Response;
+use hyper::header::ContentLength;
+use url::form_urlencoded;
+
+use core::{StubParam, StubRequest, StubResponse};
+
+/// Build a `StubRequest` from the parts of an incoming request (body already read in full).
+pub fn to_stub_request(method: &Method, uri: &Uri, headers: &Headers, body: Vec<u8>) -> StubRequest {
+    StubRequest {
+        method: Some(method.as_ref().to_owned()),
+        path: Some(uri.path().to_owned()),
+        params: parse_query(uri.query()),
+        headers: to_stub_params(headers),
+        body: if body.is_empty() { None } else { Some(body) },
+        body_type: None
+    }
+}
+
+pub fn parse_query(query: Option<&str>) -> Vec<StubParam> {
+    match query {
+        None => vec![],
+        Some(q) => {
+            form_urlencoded::parse(q.as_bytes())
+                .map(|(name, value)| StubParam {
+                    name: name.into_owned(),
+                    value: value.into_owned()
+                })
+                .collect()
+        }
+    }
+}
+
+/// Build a journal filter from query params, eg: `method=POST&path=/orders&param[foo]=bar&header[X-Trace]=abc`
+pub fn to_request_filter(params: &[StubParam]) -> Result<StubRequest, String> {
+    let mut filter = StubRequest::default();
+    for param in params {
+        match param.name.as_str() {
+            "method" => filter.method = Some(param.value.clone()),
+            "path" => filter.path = Some(param.value.clone()),
+            "wait" => {} // not part of the filter
+            name => {
+                if let Some(name) = bracketed(name, "param") {
+                    filter.params.push(StubParam { name: name.to_owned(), value: param.value.clone() });
+                } else if let Some(name) = bracketed(name, "header") {
+                    filter.headers.push(StubParam { name: name.to_owned(), value: param.value.clone() });
+                } else {
+                    return Err(format!("Unknown filter parameter: {}", name));
+                }
+            }
+        }
+    }
+    Ok(filter)
+}
+
+/// Returns `foo` given `prefix[foo]`
+fn bracketed<'a>(name: &'a str, prefix: &str) -> Option<&'a str> {
+    if name.starts_with(prefix) && name[prefix.len()..].starts_with('[') && name.ends_with(']') {
+        Some(&name[prefix.len() + 1..name.len() - 1])
+    } else {
+        None
+    }
+}
+
+/// One `StubParam` per header value (repeated headers are kept separate).
+pub fn to_stub_params(headers: &Headers) -> Vec<StubParam> {
+    let mut params = vec![];
+    for header in headers.iter() {
+        for value in header.raw().iter() {
+            params.push(StubParam {
+                name: header.name().to_owned(),
+                value: String::from_utf8_lossy(value).into_owned()
+            });
+        }
+    }
+    params
+}
+
+pub fn from_stub_response(stub: &StubResponse) -> Response {
+    let status = match StatusCode::try_from(stub.status) {
+        Ok(s) => s,
+        Err(_) => {
+            warn!("Invalid stubbed status code: {}", stub.status); // only from a script, others are validated
+            StatusCode::InternalServerError
+        }
+    };
+
+    let mut response = Response::new().with_status(status);
+
+    for header in &stub.headers {
+        response.headers_mut().append_raw(header.name.clone(), header.value.clone());
+    }
+
+    match stub.body {
+        Some(ref body) => {
+            response
+                .with_header(ContentLength(body.len() as u64))
+                .with_body(body.clone())
+        }
+        None => response.with_header(ContentLength(0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::StubParam;
+    use http::convert::{parse_query, to_request_filter};
+
+    #[test]
+    fn test_request_filter() {
+        let params = parse_query(Some("method=POST&path=%2Forders&param[foo]=bar&header[X-Trace]=abc&wait=100"));
+        let filter = to_request_filter(&params).unwrap();
+
+        assert_eq!(filter.method, Some("POST".to_owned()));
+        assert_eq!(filter.path, Some("/orders".to_owned()));
+        assert_eq!(filter.params, vec![StubParam { name: "foo".to_owned(), value: "bar".to_owned() }]);
+        assert_eq!(filter.headers, vec![StubParam { name: "X-Trace".to_owned(), value: "abc".to_owned() }]);
+
+        assert!(to_request_filter(&parse_query(Some("foo=bar"))).is_err());
+        assert!(to_request_filter(&parse_query(Some("param[foo=bar"))).is_err());
+    }
+}
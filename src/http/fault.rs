@@ -0,0 +1,128 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::Poll;
+use hyper::StatusCode;
+use hyper::server::Response;
+use rand::{self, Rng};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use core::{Fault, StubResponse};
+use http::convert;
+use http::stream;
+
+/// What to do instead of writing the response hyper has prepared.
+enum Hijack {
+    Reset,
+    Raw(Vec<u8>) // send these bytes, then close
+}
+
+/// Shared between a connection's `FaultStream` and its `HttpService`, so the service can break the connection.
+#[derive(Clone, Default)]
+pub struct FaultSwitch {
+    hijack: Rc<RefCell<Option<Hijack>>>
+}
+
+impl FaultSwitch {
+    pub fn new() -> FaultSwitch {
+        Default::default()
+    }
+
+    fn set(&self, hijack: Hijack) {
+        *self.hijack.borrow_mut() = Some(hijack);
+    }
+}
+
+/// A client connection, which can be broken on purpose when the response is written.
+pub struct FaultStream {
+    inner: TcpStream,
+    switch: FaultSwitch
+}
+
+impl FaultStream {
+    pub fn new(inner: TcpStream, switch: FaultSwitch) -> FaultStream {
+        FaultStream {
+            inner: inner,
+            switch: switch
+        }
+    }
+}
+
+impl Read for FaultStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for FaultStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut hijack = self.switch.hijack.borrow_mut();
+        match *hijack {
+            None => self.inner.write(buf),
+            Some(Hijack::Reset) => {
+                self.inner.set_linger(Some(Duration::from_secs(0)))?; // send RST when closed
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "Connection reset by stubbed fault"))
+            }
+            Some(Hijack::Raw(ref mut bytes)) => {
+                while !bytes.is_empty() {
+                    match self.inner.write(bytes)? {
+                        0 => return Err(io::ErrorKind::WriteZero.into()),
+                        n => bytes.drain(..n),
+                    };
+                }
+                Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Connection closed by stubbed fault"))
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsyncRead for FaultStream {}
+
+impl AsyncWrite for FaultStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.inner)
+    }
+}
+
+/// Build the response for a stub with a `fault`, breaking the connection through `switch` if need be.
+pub fn faulty_response(stub: &StubResponse, fault: &Fault, switch: &FaultSwitch, handle: &Handle) -> Response {
+    match *fault {
+        Fault::ResetConnection => switch.set(Hijack::Reset),
+        Fault::CloseWithoutResponse => switch.set(Hijack::Raw(vec![])),
+        Fault::MalformedChunkedBody => switch.set(Hijack::Raw(malformed_chunked(stub))),
+        Fault::RandomGarbage => {
+            let mut rng = rand::thread_rng();
+            let len = rng.gen_range(16, 1024);
+            switch.set(Hijack::Raw(rng.gen_iter::<u8>().take(len).collect()))
+        }
+        Fault::Drip { bytes, interval_ms } => {
+            let body = stub.body.clone().unwrap_or_default();
+            let interval = Duration::from_millis(interval_ms);
+            let chunks = body.chunks(bytes).map(|c| (interval, c.to_vec())).collect();
+            return convert::from_stub_response(stub).with_body(stream::delayed_chunks(chunks, handle)); // keeps length
+        }
+    }
+    convert::from_stub_response(stub) // never sent
+}
+
+/// The stubbed response with a chunk size that isn't hex, and no last chunk.
+fn malformed_chunked(stub: &StubResponse) -> Vec<u8> {
+    let status = StatusCode::try_from(stub.status).unwrap_or(StatusCode::InternalServerError);
+    let mut raw = format!("HTTP/1.1 {}\r\n", status).into_bytes();
+    for header in &stub.headers {
+        raw.extend(format!("{}: {}\r\n", header.name, header.value).into_bytes());
+    }
+    raw.extend(b"Transfer-Encoding: chunked\r\n\r\n".iter());
+    raw.extend(b"zz\r\n".iter());
+    raw.extend(stub.body.as_ref().map_or(&[][..], |b| &b[..]));
+    raw.extend(b"\r\n".iter());
+    raw
+}
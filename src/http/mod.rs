@@ -1 +1,5 @@
+pub mod convert;
+pub mod fault;
+pub mod proxy;
 pub mod service;
+pub mod stream;
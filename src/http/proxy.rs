@@ -0,0 +1,134 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use futures::{self, Future, Stream};
+use hyper::{self, Client, Method, Request, StatusCode, Uri};
+use hyper::header::ContentLength;
+use hyper::server::Response;
+use serde_json;
+use tokio_core::reactor::Handle;
+use url::form_urlencoded;
+
+use core::{StubExchange, StubParam, StubProxy, StubRequest, StubResponse};
+use core::service::StubService;
+use http::convert;
+
+/// Headers that only apply to a single connection, so are not passed on (or recorded).
+static HOP_BY_HOP_HEADERS: &'static [&'static str] = &["Connection",
+                                                       "Keep-Alive",
+                                                       "Proxy-Authenticate",
+                                                       "Proxy-Authorization",
+                                                       "TE",
+                                                       "Trailer",
+                                                       "Transfer-Encoding",
+                                                       "Upgrade",
+                                                       "Host",
+                                                       "Content-Length"];
+
+/// Forward the request to the proxy's upstream server, recording the exchange if need be.
+/// Responds with 502 (Bad Gateway) if the upstream server can't be reached.
+pub fn forward(proxy: StubProxy,
+               request: StubRequest,
+               stub_service: Arc<StubService>,
+               handle: &Handle)
+               -> Box<Future<Item = Response, Error = hyper::Error>> {
+    let upstream = match to_upstream_request(&proxy, &request) {
+        Ok(upstream) => upstream,
+        Err(message) => return Box::new(futures::future::ok(bad_gateway(&message))),
+    };
+    debug!("Forwarding request to: {}", upstream.uri());
+
+    let response = Client::new(handle).request(upstream).and_then(|res| {
+        let status = res.status().as_u16();
+        let headers = forwarded_headers(convert::to_stub_params(res.headers()));
+        res.body().concat2().map(move |body| {
+            StubResponse {
+                status: status,
+                headers: headers,
+                body: if body.is_empty() { None } else { Some(body.to_vec()) },
+                ..Default::default()
+            }
+        })
+    });
+
+    Box::new(response.then(move |result| {
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Error forwarding request to {}: {}", proxy.url, e);
+                return Ok(bad_gateway(&format!("Error forwarding request: {}", e)));
+            }
+        };
+        let response = StubResponse { status: proxy.status.unwrap_or(response.status), ..response };
+        if proxy.record {
+            match stub_service.record(&request, response.clone()) {
+                Ok(exchange) => {
+                    if let Some(ref dir) = proxy.record_dir {
+                        if let Err(e) = save(Path::new(dir), &exchange) {
+                            error!("Error saving recorded exchange to {}: {}", dir, e);
+                        }
+                    }
+                }
+                Err(e) => error!("Error recording exchange: {:?}", e),
+            }
+        }
+        Ok(convert::from_stub_response(&response))
+    }))
+}
+
+fn to_upstream_request(proxy: &StubProxy, request: &StubRequest) -> Result<Request, String> {
+    let method = request.method.as_ref().map_or("GET", |m| m).parse::<Method>().map_err(|e| e.to_string())?;
+
+    let mut uri = format!("{}{}",
+                          proxy.url.trim_end_matches('/'),
+                          proxy.path.as_ref().or(request.path.as_ref()).map_or("/", |p| p));
+    if !request.params.is_empty() {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        for param in &request.params {
+            query.append_pair(&param.name, &param.value);
+        }
+        uri.push('?');
+        uri.push_str(&query.finish());
+    }
+    let uri = uri.parse::<Uri>().map_err(|e| format!("Invalid proxy URL: {}: {}", uri, e))?;
+
+    let mut upstream = Request::new(method, uri);
+    for header in forwarded_headers(request.headers.clone()) {
+        upstream.headers_mut().append_raw(header.name, header.value);
+    }
+    for header in &proxy.headers {
+        upstream.headers_mut().remove_raw(&header.name);
+    }
+    for header in &proxy.headers {
+        upstream.headers_mut().append_raw(header.name.clone(), header.value.clone());
+    }
+    if let Some(ref body) = request.body {
+        upstream.headers_mut().set(ContentLength(body.len() as u64));
+        upstream.set_body(body.clone());
+    }
+    Ok(upstream)
+}
+
+fn forwarded_headers(headers: Vec<StubParam>) -> Vec<StubParam> {
+    headers.into_iter()
+        .filter(|h| !HOP_BY_HOP_HEADERS.iter().any(|hop| hop.eq_ignore_ascii_case(&h.name)))
+        .collect()
+}
+
+fn bad_gateway(message: &str) -> Response {
+    convert::from_stub_response(&StubResponse {
+        status: StatusCode::BadGateway.as_u16(),
+        body: Some(message.as_bytes().to_vec()),
+        ..Default::default()
+    })
+}
+
+/// Write a recorded exchange to `{dir}/{id}.json`.
+fn save(dir: &Path, exchange: &StubExchange) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let name = format!("{}.json", exchange.id.as_ref().map_or("recorded", |id| id));
+    let json = serde_json::to_string_pretty(exchange).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    File::create(dir.join(name))?.write_all(json.as_bytes())
+}
@@ -3,24 +3,44 @@ use hyper;
 use hyper::StatusCode;
 use hyper::Method;
 use hyper::server::{Request, Response, Service};
-use hyper::header::{ContentLength, ContentType};
-use futures::Future;
+use hyper::header::{ContentLength, ContentType, Location};
+use futures::{Future, Stream};
+use futures::future::Either;
 use futures::sync::mpsc;
 use futures::sink::Sink;
 use serde_json;
 use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Timeout};
 
-type FutureResult = futures::future::FutureResult<Response, hyper::Error>;
+use core::{StubExchange, StubProxy, StubRequest};
+use core::file::FileSource;
+use core::pattern::RequestPattern;
+use core::service::{JournalEntry, MatchAttempt, ServiceError, StubService};
+use http::convert;
+use http::fault::{self, FaultSwitch};
+use http::proxy;
+use http::stream;
+
+type ResponseFuture = Box<Future<Item = Response, Error = hyper::Error>>;
 
 static MSG_NOT_FOUND: &'static str = "Not found";
 static MSG_METHOD_NOT_ALLOWED: &'static str = "Method not allowed";
+static MSG_NO_STUBBED_RESPONSE: &'static str = "No stubbed response found";
 static MSG_INTERNAL_SERVER_ERROR: &'static str = "Internal server error";
 
+static HEADER_MATCH_REPORT: &'static str = "X-Stub-Match-Report";
+
 const CARGO_PKG_VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 pub struct HttpService {
     // pub shutdown_msg: String,
-    pub shutdown_promise: mpsc::Sender<()>
+    pub shutdown_promise: mpsc::Sender<()>,
+    pub stub_service: Arc<StubService>,
+    pub handle: Handle,
+    pub fault_switch: FaultSwitch,
+    pub file_source: Arc<FileSource>
 }
 
 pub fn split_path(path: &str) -> Vec<&str> {
@@ -44,60 +64,106 @@ mod tests {
     }
 }
 
-fn ok(message: &str) -> FutureResult {
-    futures::future::ok(
+fn empty_ok() -> ResponseFuture {
+    Box::new(futures::future::ok(
         Response::new()
             .with_status(StatusCode::Ok)
-            .with_header(ContentType::plaintext())
-            .with_header(ContentLength(message.len() as u64))
-            .with_body(message.to_owned())
-    )
+            .with_header(ContentLength(0))
+    ))
+}
+
+/// Send the response once `delay` has passed, without blocking the reactor.
+fn delayed(response: Response, delay: Duration, handle: &Handle) -> ResponseFuture {
+    match Timeout::new(delay, handle) {
+        Ok(timeout) => Box::new(timeout.map(move |_| response).map_err(hyper::Error::from)),
+        Err(e) => Box::new(futures::future::err(e.into())),
+    }
+}
+
+/// Response to adding a stubbed exchange, with its new ID in the body and `Location` header.
+fn created(id: &str) -> ResponseFuture {
+    let body = CreatedResponse { id: id.to_owned() };
+    Box::new(json_response(StatusCode::Created, &body).map(move |response| {
+        response.with_header(Location::new(format!("/_control/responses/{}", body.id)))
+    }))
 }
 
-fn ok_json<T: Serialize>(obj: &T) -> FutureResult {
+fn ok_json<T: Serialize>(obj: &T) -> ResponseFuture {
+    json_response(StatusCode::Ok, obj)
+}
+
+fn json_response<T: Serialize>(status: StatusCode, obj: &T) -> ResponseFuture {
     return match serde_json::to_string(&obj) {
         Err(e) => {
             error!("Error serialising JSON response: {}", e);
             internal_server_error()
         }
-        Ok(body) => futures::future::ok(
+        Ok(body) => Box::new(futures::future::ok(
             Response::new()
-                .with_status(StatusCode::Ok)
+                .with_status(status)
                 .with_header(ContentType::json())
                 .with_header(ContentLength(body.len() as u64))
                 .with_body(body)
-        ),
+        )),
     };
 }
 
-fn internal_server_error() -> FutureResult {
-    futures::future::ok(
+fn internal_server_error() -> ResponseFuture {
+    Box::new(futures::future::ok(
         Response::new()
             .with_status(StatusCode::InternalServerError)
             .with_header(ContentType::plaintext())
             .with_header(ContentLength(MSG_INTERNAL_SERVER_ERROR.len() as u64))
             .with_body(MSG_INTERNAL_SERVER_ERROR)
-    )
+    ))
 }
 
-fn not_found() -> FutureResult {
-    futures::future::ok(
+fn not_found() -> ResponseFuture {
+    Box::new(futures::future::ok(
         Response::new()
             .with_status(StatusCode::NotFound)
             .with_header(ContentType::plaintext())
             .with_header(ContentLength(MSG_NOT_FOUND.len() as u64))
             .with_body(MSG_NOT_FOUND)
-    )
+    ))
 }
 
-fn method_not_allowed() -> FutureResult {
-    futures::future::ok(
+fn not_found_message(message: &str) -> ResponseFuture {
+    Box::new(futures::future::ok(
+        Response::new()
+            .with_status(StatusCode::NotFound)
+            .with_header(ContentType::plaintext())
+            .with_header(ContentLength(message.len() as u64))
+            .with_body(message.to_owned())
+    ))
+}
+
+fn bad_request(message: &str) -> ResponseFuture {
+    Box::new(futures::future::ok(
+        Response::new()
+            .with_status(StatusCode::BadRequest)
+            .with_header(ContentType::plaintext())
+            .with_header(ContentLength(message.len() as u64))
+            .with_body(message.to_owned())
+    ))
+}
+
+fn service_error(error: ServiceError) -> ResponseFuture {
+    match error {
+        ServiceError::NotFound(message) => not_found_message(&message),
+        ServiceError::InvalidPattern(message) => bad_request(&message),
+        ServiceError::InvalidExchange(message) => bad_request(&message),
+    }
+}
+
+fn method_not_allowed() -> ResponseFuture {
+    Box::new(futures::future::ok(
         Response::new()
             .with_status(StatusCode::MethodNotAllowed)
             .with_header(ContentType::plaintext())
             .with_header(ContentLength(MSG_METHOD_NOT_ALLOWED.len() as u64))
             .with_body(MSG_METHOD_NOT_ALLOWED)
-    )
+    ))
 }
 
 #[derive(Serialize)]
@@ -105,27 +171,100 @@ struct VersionResponse {
     version: String
 }
 
+#[derive(Serialize)]
+struct CreatedResponse {
+    id: String
+}
+
+#[derive(Serialize)]
+struct DeletedResponse {
+    deleted: usize
+}
+
+/// Body of `PUT /_control/scenarios/{name}`.
+#[derive(Deserialize)]
+struct ScenarioState {
+    state: String
+}
+
+/// Sent instead of the plain 'not found' message when a request has the `X-Stub-Match-Report` header.
+#[derive(Serialize)]
+struct MatchReport<'a> {
+    message: &'a str,
+    attempts: &'a [MatchAttempt]
+}
+
 // TODO: https://hyper.rs/guides/server/echo/
 
 impl HttpService {
-    fn handle(&self, req: &Request, path: &[&str]) -> FutureResult {
+    fn handle(&self, req: Request, path: &[&str]) -> ResponseFuture {
         match path {
-            &["_control", ref tail..] => self.handle_control(req, tail),
-            _ => ok("TODO: match request ..."),
+            &["_control", ref tail @ ..] => self.handle_control(req, tail),
+            _ => self.handle_match(req),
         }
     }
 
-    fn handle_control(&self, req: &Request, path: &[&str]) -> FutureResult {
+    fn handle_match(&self, req: Request) -> ResponseFuture {
+        let stub_service = self.stub_service.clone();
+        let handle = self.handle.clone();
+        let fault_switch = self.fault_switch.clone();
+        let (method, uri, _, headers, body) = req.deconstruct();
+
+        Box::new(body.concat2().and_then(move |body| -> ResponseFuture {
+            let request = convert::to_stub_request(&method, &uri, &headers, body.to_vec());
+            let result = stub_service.find_match(&request);
+            let proxy = match result.proxy {
+                Some(ref proxy) => Some(proxy.clone()),
+                None if !result.matched() => stub_service.get_proxy_for(&request), // forward anything unmatched
+                None => None,
+            };
+            if let Some(proxy) = proxy {
+                let delay = result.delay.unwrap_or(0);
+                let forwarded = proxy::forward(proxy, request, stub_service.clone(), &handle);
+                return match delay {
+                    0 => forwarded,
+                    ms => Box::new(forwarded.and_then(move |response| {
+                        delayed(response, Duration::from_millis(ms as u64), &handle)
+                    })),
+                };
+            }
+            match result.response {
+                Some(ref response) => {
+                    let response = match response.fault {
+                        Some(ref f) => fault::faulty_response(response, f, &fault_switch, &handle),
+                        None if !response.chunks.is_empty() => stream::streamed_response(response, &handle),
+                        None => convert::from_stub_response(response),
+                    };
+                    match result.delay {
+                        Some(ms) if ms > 0 => delayed(response, Duration::from_millis(ms as u64), &handle),
+                        _ => Box::new(futures::future::ok(response)),
+                    }
+                }
+                None if headers.get_raw(HEADER_MATCH_REPORT).is_some() => {
+                    json_response(StatusCode::NotFound, &MatchReport {
+                        message: MSG_NO_STUBBED_RESPONSE,
+                        attempts: &result.attempts
+                    })
+                }
+                None => not_found_message(MSG_NO_STUBBED_RESPONSE),
+            }
+        }))
+    }
+
+    fn handle_control(&self, req: Request, path: &[&str]) -> ResponseFuture {
         match path {
-            &["shutdown"] => self.handle_control_shutdown(req),
-            &["version"] => self.handle_control_version(req),
-            &["responses", ref tail..] => self.handle_control_responses(req, tail),
-            &["requests", ref tail..] => self.handle_control_requests(req, tail),
+            &["shutdown"] => self.handle_control_shutdown(&req),
+            &["version"] => self.handle_control_version(&req),
+            &["responses", ref tail @ ..] => self.handle_control_responses(req, tail),
+            &["requests", ref tail @ ..] => self.handle_control_requests(&req, tail),
+            &["scenarios", ref tail @ ..] => self.handle_control_scenarios(req, tail),
+            &["proxy"] => self.handle_control_proxy(req),
+            &["files"] => self.handle_control_files(&req),
             _ => not_found(),
         }
     }
 
-    fn handle_control_shutdown(&self, req: &Request) -> FutureResult {
+    fn handle_control_shutdown(&self, req: &Request) -> ResponseFuture {
         match *req.method() {
             // Method::Post => ok("TODO: POST /_control/shutdown"),
             Method::Post => {
@@ -137,19 +276,19 @@ impl HttpService {
 
                 let shutdown_msg = "{\"message\": \"Shutdown triggered\"}";
 
-                futures::future::ok(
+                Box::new(futures::future::ok(
                     Response::new()
                         .with_status(StatusCode::Accepted)
                         .with_header(ContentType::plaintext())
                         .with_header(ContentLength(shutdown_msg.len() as u64))
-                        .with_body(shutdown_msg))
+                        .with_body(shutdown_msg)))
                         // .with_body(self.shutdown_msg.clone()))
             }
             _ => method_not_allowed(),
         }
     }
 
-    fn handle_control_version(&self, req: &Request) -> FutureResult {
+    fn handle_control_version(&self, req: &Request) -> ResponseFuture {
         let version = VersionResponse { version: CARGO_PKG_VERSION.to_owned() };
         match *req.method() {
             Method::Get => ok_json(&version),
@@ -157,20 +296,39 @@ impl HttpService {
         }
     }
 
-    fn handle_control_responses(&self, req: &Request, path: &[&str]) -> FutureResult {
+    fn handle_control_responses(&self, req: Request, path: &[&str]) -> ResponseFuture {
         match path {
             &[] => {
+                match req.method().clone() {
+                    Method::Get => ok_json(&self.stub_service.get_responses()),
+                    Method::Delete => {
+                        self.stub_service.delete_responses();
+                        empty_ok()
+                    }
+                    Method::Post => self.add_response(req),
+                    _ => method_not_allowed(),
+                }
+            }
+            &["delete"] => {
                 match *req.method() {
-                    Method::Get => ok("TODO: GET /_control/responses"),
-                    Method::Delete => ok("TODO: DELETE /_control/responses"),
-                    Method::Post => ok("TODO: POST /_control/responses"),
+                    Method::Delete | Method::Post => self.delete_matching_responses(req),
                     _ => method_not_allowed(),
                 }
             }
             &[id] => {
                 match *req.method() {
-                    Method::Get => ok("TODO: GET /_control/responses/{id}"),
-                    Method::Delete => ok("TODO: DELETE /_control/responses/{id}"),
+                    Method::Get => {
+                        match self.stub_service.get_response(id) {
+                            Ok(exchange) => ok_json(&exchange),
+                            Err(e) => service_error(e),
+                        }
+                    }
+                    Method::Delete => {
+                        match self.stub_service.delete_response(id) {
+                            Ok(_) => empty_ok(),
+                            Err(e) => service_error(e),
+                        }
+                    }
                     _ => method_not_allowed(),
                 }
             }
@@ -178,36 +336,221 @@ impl HttpService {
         }
     }
 
-    fn handle_control_requests(&self, req: &Request, path: &[&str]) -> FutureResult {
+    fn add_response(&self, req: Request) -> ResponseFuture {
+        let stub_service = self.stub_service.clone();
+
+        Box::new(req.body().concat2().and_then(move |body| {
+            match serde_json::from_slice::<StubExchange>(&body) {
+                Ok(exchange) => {
+                    match stub_service.add_response(exchange) {
+                        Ok(id) => created(&id),
+                        Err(e) => service_error(e),
+                    }
+                }
+                Err(e) => bad_request(&format!("Error parsing stubbed exchange: {}", e)),
+            }
+        }))
+    }
+
+    /// Delete the exchanges whose request pattern matches the `StubRequest` in the body.
+    fn delete_matching_responses(&self, req: Request) -> ResponseFuture {
+        let stub_service = self.stub_service.clone();
+
+        Box::new(req.body().concat2().and_then(move |body| {
+            match serde_json::from_slice::<StubRequest>(&body) {
+                Ok(request) => ok_json(&DeletedResponse { deleted: stub_service.delete_matching_responses(&request) }),
+                Err(e) => bad_request(&format!("Error parsing request: {}", e)),
+            }
+        }))
+    }
+
+    fn find_requests(&self, req: &Request) -> ResponseFuture {
+        let params = convert::parse_query(req.query());
+        let wait = match params.iter().find(|p| p.name == "wait") {
+            None => 0,
+            Some(p) => {
+                match p.value.parse::<u64>() {
+                    Ok(wait) => wait,
+                    Err(_) => return bad_request(&format!("Invalid 'wait' parameter: {}", p.value)),
+                }
+            }
+        };
+
+        let filter = match convert::to_request_filter(&params) {
+            Ok(filter) => filter,
+            Err(message) => return bad_request(&message),
+        };
+        let filter = match RequestPattern::new(&filter) {
+            Ok(filter) => filter,
+            Err(e) => return bad_request(&format!("Invalid request filter: {}", e)),
+        };
+
+        if wait > 0 {
+            let deadline = Instant::now() + Duration::from_millis(wait);
+            Box::new(wait_for_requests(self.stub_service.clone(), filter, deadline, self.handle.clone())
+                .and_then(|found| ok_json(&found)))
+        } else {
+            ok_json(&self.stub_service.find_requests(&filter))
+        }
+    }
+
+    fn handle_control_requests(&self, req: &Request, path: &[&str]) -> ResponseFuture {
         match path {
             &[] => {
                 match *req.method() {
-                    Method::Get => ok("TODO: GET /_control/requests"), // TODO: 'wait' parameter
-                    Method::Delete => ok("TODO: DELETE /_control/requests"),
+                    Method::Get => self.find_requests(req),
+                    Method::Delete => {
+                        self.stub_service.delete_requests();
+                        empty_ok()
+                    }
                     _ => method_not_allowed(),
                 }
             }
             &[id] => {
+                let index = match id.parse::<usize>() {
+                    Ok(index) => index,
+                    Err(_) => return not_found(),
+                };
+                match *req.method() {
+                    Method::Get => {
+                        match self.stub_service.get_request(index) {
+                            Ok(entry) => ok_json(&entry),
+                            Err(e) => service_error(e),
+                        }
+                    }
+                    Method::Delete => {
+                        match self.stub_service.delete_request(index) {
+                            Ok(_) => empty_ok(),
+                            Err(e) => service_error(e),
+                        }
+                    }
+                    _ => method_not_allowed(),
+                }
+            }
+            _ => not_found(),
+        }
+    }
+
+    fn handle_control_files(&self, req: &Request) -> ResponseFuture {
+        match *req.method() {
+            Method::Get => ok_json(&self.file_source.get_files()),
+            _ => method_not_allowed(),
+        }
+    }
+
+    fn handle_control_proxy(&self, req: Request) -> ResponseFuture {
+        match req.method().clone() {
+            Method::Get => {
+                match self.stub_service.get_proxy() {
+                    Some(proxy) => ok_json(&proxy),
+                    None => not_found_message("No proxy configured"),
+                }
+            }
+            Method::Put => self.set_proxy(req),
+            Method::Delete => {
+                match self.stub_service.set_proxy(None) {
+                    Ok(_) => empty_ok(),
+                    Err(e) => service_error(e),
+                }
+            }
+            _ => method_not_allowed(),
+        }
+    }
+
+    fn set_proxy(&self, req: Request) -> ResponseFuture {
+        let stub_service = self.stub_service.clone();
+
+        Box::new(req.body().concat2().and_then(move |body| {
+            match serde_json::from_slice::<StubProxy>(&body) {
+                Ok(proxy) => {
+                    match stub_service.set_proxy(Some(proxy)) {
+                        Ok(_) => empty_ok(),
+                        Err(e) => service_error(e),
+                    }
+                }
+                Err(e) => bad_request(&format!("Error parsing proxy: {}", e)),
+            }
+        }))
+    }
+
+    fn handle_control_scenarios(&self, req: Request, path: &[&str]) -> ResponseFuture {
+        match path {
+            &[] => {
                 match *req.method() {
-                    Method::Get => ok("TODO: GET /_control/requests/{id}"),
-                    Method::Delete => ok("TODO: DELETE /_control/requests/{id}"),
+                    Method::Get => ok_json(&self.stub_service.get_scenarios()),
+                    Method::Delete => {
+                        self.stub_service.reset_scenarios();
+                        empty_ok()
+                    }
+                    _ => method_not_allowed(),
+                }
+            }
+            &[name] => {
+                match req.method().clone() {
+                    Method::Put => self.set_scenario_state(name, req),
+                    Method::Delete => {
+                        self.stub_service.reset_scenario(name);
+                        empty_ok()
+                    }
                     _ => method_not_allowed(),
                 }
             }
             _ => not_found(),
         }
     }
+
+    fn set_scenario_state(&self, name: &str, req: Request) -> ResponseFuture {
+        let stub_service = self.stub_service.clone();
+        let name = name.to_owned();
+
+        Box::new(req.body().concat2().and_then(move |body| {
+            match serde_json::from_slice::<ScenarioState>(&body) {
+                Ok(state) => {
+                    stub_service.set_scenario_state(&name, &state.state);
+                    empty_ok()
+                }
+                Err(e) => bad_request(&format!("Error parsing scenario state: {}", e)),
+            }
+        }))
+    }
+}
+
+/// Resolves with the journal entries matching `filter`, once there are any or `deadline` has passed.
+fn wait_for_requests(stub_service: Arc<StubService>,
+                     filter: RequestPattern,
+                     deadline: Instant,
+                     handle: Handle)
+                     -> Box<Future<Item = Vec<JournalEntry>, Error = hyper::Error>> {
+    let notified = stub_service.notify_on_request(); // register first, so no request is missed
+    let found = stub_service.find_requests(&filter);
+
+    let now = Instant::now();
+    if !found.is_empty() || now >= deadline {
+        return Box::new(futures::future::ok(found));
+    }
+
+    let timeout = match Timeout::new(deadline - now, &handle) {
+        Ok(t) => t,
+        Err(e) => return Box::new(futures::future::err(e.into())),
+    };
+
+    Box::new(notified.select2(timeout).then(move |result| -> Box<Future<Item = _, Error = _>> {
+        match result {
+            Ok(Either::A(_)) => wait_for_requests(stub_service, filter, deadline, handle), // look again
+            _ => Box::new(futures::future::ok(stub_service.find_requests(&filter))), // timed out
+        }
+    }))
 }
 
 impl Service for HttpService {
     type Request = Request;
     type Response = Response;
     type Error = hyper::Error;
-    //    type Future = futures::future::FutureResult<Self::Response, Self::Error>;
-    type Future = FutureResult;
+    type Future = ResponseFuture;
 
     fn call(&self, req: Request) -> Self::Future {
-        self.handle(&req, &split_path(&req.path())[..])
+        let path = req.path().to_owned();
+        self.handle(req, &split_path(&path)[..])
     }
 }
 
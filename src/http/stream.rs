@@ -0,0 +1,44 @@
+use futures::{self, Future, Sink, Stream};
+use hyper::{self, Body, Chunk};
+use hyper::header::{ContentLength, TransferEncoding};
+use hyper::server::Response;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+
+use core::StubResponse;
+use http::convert;
+
+/// Send the stubbed response's `chunks` as they become due, using `Transfer-Encoding: chunked`.
+pub fn streamed_response(stub: &StubResponse, handle: &Handle) -> Response {
+    let chunks = stub.chunks
+        .iter()
+        .map(|c| {
+            let delay = c.delay.as_ref().map_or(0, |d| d.sample());
+            (Duration::from_millis(delay as u64), c.body.clone().unwrap_or_default())
+        })
+        .collect();
+
+    let mut response = convert::from_stub_response(stub);
+    response.headers_mut().remove::<ContentLength>();
+    response.headers_mut().set(TransferEncoding::chunked());
+    response.with_body(delayed_chunks(chunks, handle))
+}
+
+/// A response body that sends each chunk after its delay, without blocking the reactor.
+pub fn delayed_chunks(chunks: Vec<(Duration, Vec<u8>)>, handle: &Handle) -> Body {
+    let (sender, body) = Body::pair();
+
+    let timer = handle.clone();
+    let chunks = futures::stream::iter_ok(chunks).and_then(move |(delay, chunk)| {
+        futures::future::result(Timeout::new(delay, &timer))
+            .flatten()
+            .map(move |_| Ok(Chunk::from(chunk)))
+            .map_err(hyper::Error::from)
+    });
+
+    handle.spawn(sender.sink_map_err(|_| ()) // client may have gone away
+        .send_all(chunks.map_err(|e| error!("Error streaming response body: {}", e)))
+        .map(|_| ()));
+
+    body
+}
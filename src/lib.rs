@@ -1,24 +1,37 @@
-#![feature(slice_patterns)]
-
 extern crate hyper;
 extern crate futures;
+extern crate url;
+extern crate regex;
+extern crate xml;
+extern crate uuid;
+extern crate rand;
+extern crate rhai;
+extern crate chrono;
+extern crate tokio_core;
+extern crate tokio_io;
 
 extern crate serde;
 // #[macro_use]
 extern crate serde_json;
+extern crate serde_yaml;
 #[macro_use]
 extern crate serde_derive;
 
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate lazy_static;
 
 mod core;
 mod http;
 
+use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::RecvTimeoutError;
 use std::result::Result;
+use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
 
@@ -26,15 +39,23 @@ use std::time::Duration;
 
 use hyper::server::Http;
 
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Interval};
+
 use futures::Future;
 use futures::sync::oneshot;
 use futures::sync::mpsc;
 use futures::stream::Stream;
 use futures::sink::Sink;
 
+use core::file::FileSource;
+use core::service::StubService;
+use http::fault::{FaultStream, FaultSwitch};
+
 #[derive(Debug)]
 pub enum ServerError {
     Hyper(hyper::Error),
+    Io(io::Error),
     Assertion(&'static str)
 }
 
@@ -44,6 +65,19 @@ impl From<hyper::Error> for ServerError {
     }
 }
 
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> ServerError {
+        ServerError::Io(e)
+    }
+}
+
+/// Settings for `Server::start_with()`.
+#[derive(Clone, Debug, Default)]
+pub struct ServerOptions {
+    pub stub_dirs: Vec<PathBuf>, // `*.json` and `*.yaml` stub files are loaded from these before listening
+    pub watch_interval: Option<Duration> // how often to look for changed stub files (default 1 second)
+}
+
 pub struct Server {
     local_addr: SocketAddr,
     shutdown_promise: mpsc::Sender<()>,
@@ -52,6 +86,10 @@ pub struct Server {
 
 impl Server {
     pub fn start(addr: SocketAddr) -> Result<Server, ServerError> {
+        Server::start_with(addr, ServerOptions::default())
+    }
+
+    pub fn start_with(addr: SocketAddr, options: ServerOptions) -> Result<Server, ServerError> {
 
         let (shutdown_sender, shutdown_receiver) = mpsc::channel::<()>(1);
 
@@ -61,7 +99,9 @@ impl Server {
         let (startup_promise, startup_future) = oneshot::channel::<SocketAddr>();
 
         let shutdown_promise_arg = shutdown_promise.clone();
-        let child = thread::spawn(move || Server::run(addr, shutdown_promise_arg, shutdown_future, startup_promise));
+        let child = thread::spawn(move || {
+            Server::run(addr, options, shutdown_promise_arg, shutdown_future, startup_promise)
+        });
 
         let actual_addr = match startup_future.wait() {
             Ok(_addr) => _addr,
@@ -144,6 +184,7 @@ impl Server {
     }
 
     fn run<F, I, E>(addr: SocketAddr,
+           options: ServerOptions,
         //    shutdown_future: oneshot::Receiver<()>,
            shutdown_promise: mpsc::Sender<()>,
            shutdown_future: F, 
@@ -157,25 +198,62 @@ impl Server {
         //     t.map_err(|_| ()).map(|_| ())
         // }
 
-        let server = Http::new().bind(&addr, move || Ok(http::service::HttpService { 
-            shutdown_promise: shutdown_promise.clone()
-        }));
+        let mut core = Core::new()?;
+        let handle = core.handle();
 
-        // if server.is_err() {
-        //     error!("Could not bind to address: {}", addr); // TODO: print IO error description here
-        //     startup_promise.send(BindResult::BindError);
-        //     return Err(server.map(|_| ()));
-        // }
+        let stub_service = Arc::new(StubService::new());
+
+        let watch = !options.stub_dirs.is_empty();
+        let file_source = Arc::new(FileSource::new(options.stub_dirs, stub_service.clone()));
+        let loaded = file_source.load_initial_files()?;
+        if loaded > 0 {
+            info!("Loaded {} stubbed exchange(s) from files", loaded);
+        }
 
-        let server = server.unwrap();
+        if watch {
+            let watched = file_source.clone();
+            let interval = options.watch_interval.unwrap_or(Duration::from_secs(1));
+            let watcher = Interval::new(interval, &handle)?.for_each(move |_| {
+                let changed = watched.reload_changed();
+                if changed > 0 {
+                    info!("Reloaded {} changed stub file(s)", changed);
+                }
+                Ok(())
+            });
+            handle.spawn(watcher.map_err(|e| error!("Error watching stub files: {}", e)));
+        }
+
+        let listener = TcpListener::bind(&addr, &handle)?;
 
         // return actual listening address to parent thread
-        startup_promise.send(server.local_addr()?).map_err(|_| {
+        startup_promise.send(listener.local_addr()?).map_err(|_| {
             ServerError::Assertion("Could not return address to parent thread")
         })?;
 
-        server.run_until(shutdown_future.map_err(|_| ()).map(|_| ()))?;
-        // server.run_until(_strip(shutdown_future))?;
+        // run each connection on the same reactor, so services can share its handle (eg, for timeouts)
+        let protocol = Http::<hyper::Chunk>::new();
+        let connections = listener.incoming().for_each(move |(socket, _)| {
+            let fault_switch = FaultSwitch::new(); // lets the service break this connection on purpose
+            let service = http::service::HttpService {
+                shutdown_promise: shutdown_promise.clone(),
+                stub_service: stub_service.clone(),
+                handle: handle.clone(),
+                fault_switch: fault_switch.clone(),
+                file_source: file_source.clone()
+            };
+            let conn = protocol.serve_connection(FaultStream::new(socket, fault_switch), service);
+            handle.spawn(conn.map(|_| ()).map_err(|e| error!("Server connection error: {}", e)));
+            Ok(())
+        });
+
+        let shutdown = shutdown_future.map_err(|_| ()).map(|_| ());
+
+        match core.run(connections.map_err(ServerError::from).select(shutdown.map_err(|_| {
+            ServerError::Assertion("Error waiting for shutdown message")
+        }))) {
+            Ok(_) => {}
+            Err((e, _)) => return Err(e),
+        }
 
         Ok(()) // clean shutdown
     }
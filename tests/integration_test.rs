@@ -10,24 +10,27 @@ extern crate lazy_static;
 extern crate futures;
 extern crate hyper;
 extern crate tokio_core;
+#[macro_use]
 extern crate serde_json;
 extern crate regex;
 
 use std::io;
 use std::io::Write;
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use futures::Future;
 use futures::Stream;
 
-use hyper::header::{ContentLength, ContentType};
+use hyper::header::{ContentLength, ContentType, Location, TransferEncoding};
+use hyper::Headers;
 use hyper::{Chunk, Client, StatusCode, Method, Request};
 
 use tokio_core::reactor::Core;
 
 use serde_json::Value;
 
-use stubby::Server;
+use stubby::{Server, ServerOptions};
 
 use regex::Regex;
 
@@ -56,6 +59,36 @@ fn before() {
     assert!(l.is_ok());
 }
 
+/// Send a request and wait for the full response.
+fn send(core: &mut Core, req: Request) -> (StatusCode, Headers, Vec<u8>) {
+    try_send(core, req).unwrap()
+}
+
+fn try_send(core: &mut Core, req: Request) -> Result<(StatusCode, Headers, Vec<u8>), hyper::Error> {
+    let client = Client::new(&core.handle());
+
+    let work = client.request(req).and_then(|res| {
+        let status = res.status();
+        let headers = res.headers().clone();
+        res.body().concat2().map(move |body: Chunk| (status, headers, body.to_vec()))
+    });
+
+    core.run(work)
+}
+
+fn request(server: &Server, method: Method, path: &str) -> Request {
+    let uri = format!("http://{}{}", server.local_addr(), path).parse().unwrap();
+    Request::new(method, uri)
+}
+
+fn json_request(server: &Server, method: Method, path: &str, json: &str) -> Request {
+    let mut req = request(server, method, path);
+    req.headers_mut().set(ContentType::json());
+    req.headers_mut().set(ContentLength(json.len() as u64));
+    req.set_body(json.to_owned());
+    req
+}
+
 // TODO: always run with '--test-threads'
 
 // TODO: https://medium.com/@ericdreichert/test-setup-and-teardown-in-rust-without-a-framework-ba32d97aa5ab
@@ -147,4 +180,764 @@ fn test_shutdown() {
     server.join_timeout(Duration::from_secs(5)).expect("Clean shutdown");
 
     info!("Server shutdown.");
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_no_match() {
+    before();
+
+    let server = start_server();
+
+    let mut core = Core::new().unwrap();
+    let client = Client::new(&core.handle());
+
+    let uri = format!("http://{}/some/path?foo=bar", server.local_addr()).parse().unwrap();
+
+    let work = client.get(uri).and_then(|res| {
+
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        res.body().concat2().and_then(move |body: Chunk| {
+            assert_eq!(&body[..], b"No stubbed response found");
+            Ok(())
+        })
+
+    });
+
+    core.run(work).unwrap();
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_add_and_match_response() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let exchange = r#"{
+        "request": {
+            "method": "GET",
+            "path": "/orders/123",
+            "params": [{"name": "expand", "value": "true"}]
+        },
+        "response": {
+            "status": 200,
+            "headers": [{"name": "Content-Type", "value": "application/json"}],
+            "body": {"id": 123}
+        }
+    }"#;
+
+    let (status, headers, body) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", exchange));
+    assert_eq!(status, StatusCode::Created);
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    let id = json["id"].as_str().unwrap().to_owned();
+    let location = format!("/_control/responses/{}", id);
+    assert_eq!(headers.get::<Location>().map(|l| l as &str), Some(&location as &str));
+
+    let (status, headers, body) = send(&mut core, request(&server, Method::Get, "/orders/123?expand=true"));
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(headers.get::<ContentType>(), Some(&ContentType::json()));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["id"], 123);
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/orders/123"));
+    assert_eq!(status, StatusCode::NotFound);
+
+    let (status, _, body) = send(&mut core, request(&server, Method::Get, "/_control/responses"));
+    assert_eq!(status, StatusCode::Ok);
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 1);
+    assert_eq!(json[0]["request"]["path"], "/orders/123");
+    assert_eq!(json[0]["id"], id);
+
+    let (status, _, body) = send(&mut core, request(&server, Method::Get, &location));
+    assert_eq!(status, StatusCode::Ok);
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["id"], id);
+    assert_eq!(json["response"]["status"], 200);
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Delete, &location));
+    assert_eq!(status, StatusCode::Ok);
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, &location));
+    assert_eq!(status, StatusCode::NotFound);
+
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", "{}"));
+    assert_eq!(status, StatusCode::BadRequest);
+
+    let invalid_status = r#"{"request": {"path": "/orders"}, "response": {"status": 1000}}"#;
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", invalid_status));
+    assert_eq!(status, StatusCode::BadRequest);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_request_journal() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    send(&mut core, request(&server, Method::Get, "/first"));
+    send(&mut core, json_request(&server, Method::Put, "/second?foo=bar", "{\"id\": 1}"));
+
+    let (status, _, body) = send(&mut core, request(&server, Method::Get, "/_control/requests"));
+    assert_eq!(status, StatusCode::Ok);
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 2);
+    assert_eq!(json[0]["request"]["method"], "PUT"); // most recent first
+    assert_eq!(json[0]["request"]["path"], "/second");
+    assert_eq!(json[0]["request"]["params"][0]["value"], "bar");
+    assert_eq!(json[0]["request"]["body"], "{\"id\": 1}");
+    assert_eq!(json[0]["matched"], false);
+    assert!(json[0]["timestamp"].is_string());
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Delete, "/_control/requests/0"));
+    assert_eq!(status, StatusCode::Ok);
+
+    let (status, _, body) = send(&mut core, request(&server, Method::Get, "/_control/requests/0"));
+    assert_eq!(status, StatusCode::Ok);
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["request"]["path"], "/first");
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Delete, "/_control/requests"));
+    assert_eq!(status, StatusCode::Ok);
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/_control/requests/0"));
+    assert_eq!(status, StatusCode::NotFound);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_wait_for_request() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    // nothing arrives, so wait until timeout
+    let started = Instant::now();
+    let (status, _, body) = send(&mut core, request(&server, Method::Get, "/_control/requests?wait=200"));
+    assert_eq!(status, StatusCode::Ok);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert!(json.as_array().unwrap().is_empty());
+
+    // request arrives while waiting
+    let uri: hyper::Uri = format!("http://{}/async", server.local_addr()).parse().unwrap();
+    let sender = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        core.run(client.get(uri)).unwrap();
+    });
+
+    let started = Instant::now();
+    let (status, _, body) = send(&mut core, request(&server, Method::Get, "/_control/requests?wait=10000"));
+    assert_eq!(status, StatusCode::Ok);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json[0]["request"]["path"], "/async");
+
+    sender.join().unwrap();
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/_control/requests?wait=soon"));
+    assert_eq!(status, StatusCode::BadRequest);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_filter_requests() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    send(&mut core, request(&server, Method::Get, "/orders?foo=bar"));
+    send(&mut core, json_request(&server, Method::Post, "/orders", "{}"));
+    send(&mut core, request(&server, Method::Get, "/customers"));
+
+    let (status, _, body) = send(&mut core, request(&server, Method::Get, "/_control/requests?path=/orders"));
+    assert_eq!(status, StatusCode::Ok);
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 2);
+
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/_control/requests?method=POST&path=/orders"));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 1);
+    assert_eq!(json[0]["request"]["method"], "POST");
+
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/_control/requests?param[foo]=bar"));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 1);
+    assert_eq!(json[0]["request"]["method"], "GET");
+
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/_control/requests?header[content-type]=application/json"));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 1);
+    assert_eq!(json[0]["request"]["method"], "POST");
+
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/_control/requests?path=/(orders|customers).*"));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 3);
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/_control/requests?foo=bar"));
+    assert_eq!(status, StatusCode::BadRequest);
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/_control/requests?path=/orders("));
+    assert_eq!(status, StatusCode::BadRequest);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_regex_match() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let exchange = r#"{
+        "request": {
+            "method": "GET|HEAD",
+            "path": "/orders/\\d+",
+            "headers": [{"name": "accept", "value": "application/.*json"}]
+        },
+        "response": {"status": 200, "body": "matched"}
+    }"#;
+
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", exchange));
+    assert_eq!(status, StatusCode::Created);
+
+    let mut req = request(&server, Method::Get, "/orders/123");
+    req.headers_mut().set_raw("Accept", "application/vnd.api+json");
+    let (status, _, body) = send(&mut core, req);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(&body[..], b"matched");
+
+    let mut req = request(&server, Method::Get, "/orders/abc");
+    req.headers_mut().set_raw("Accept", "application/json");
+    let (status, _, _) = send(&mut core, req);
+    assert_eq!(status, StatusCode::NotFound);
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/orders/123")); // no header
+    assert_eq!(status, StatusCode::NotFound);
+
+    let invalid = r#"{"request": {"path": "/orders/("}, "response": {"status": 200}}"#;
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", invalid));
+    assert_eq!(status, StatusCode::BadRequest);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_match_report() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let exchange = r#"{
+        "request": {"method": "GET", "path": "/orders/\\d+", "params": [{"name": "expand", "value": "true"}]},
+        "response": {"status": 200}
+    }"#;
+    send(&mut core, json_request(&server, Method::Post, "/_control/responses", exchange));
+
+    let mut req = request(&server, Method::Get, "/orders/123?expand=false");
+    req.headers_mut().set_raw("X-Stub-Match-Report", "true");
+    let (status, headers, body) = send(&mut core, req);
+    assert_eq!(status, StatusCode::NotFound);
+    assert_eq!(headers.get::<ContentType>(), Some(&ContentType::json()));
+
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["message"], "No stubbed response found");
+    let fields = &json["attempts"][0]["result"]["fields"];
+    assert_eq!(json["attempts"][0]["request"]["path"], "/orders/\\d+");
+    assert_eq!(json["attempts"][0]["result"]["matches"], false);
+    assert_eq!(fields[1]["field"], "path");
+    assert_eq!(fields[1]["matches"], true);
+    assert_eq!(fields[2]["field"], "param[expand]");
+    assert_eq!(fields[2]["matches"], false);
+    assert_eq!(fields[2]["expected"], "true");
+    assert_eq!(fields[2]["actual"], "false");
+
+    // also kept in the journal, by stub ID rather than with a copy of the stubbed request
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/_control/requests/0"));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["attempts"][0]["result"]["fields"][2]["actual"], "false");
+    assert!(json["attempts"][0]["id"].is_string());
+    assert!(json["attempts"][0].get("request").is_none());
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_delay() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let slow = r#"{"request": {"path": "/slow"}, "response": {"status": 200}, "delay": 500}"#;
+    let fast = r#"{"request": {"path": "/fast"}, "response": {"status": 200}}"#;
+    send(&mut core, json_request(&server, Method::Post, "/_control/responses", slow));
+    send(&mut core, json_request(&server, Method::Post, "/_control/responses", fast));
+
+    let uri: hyper::Uri = format!("http://{}/slow", server.local_addr()).parse().unwrap();
+    let slow_request = thread::spawn(move || {
+        let started = Instant::now();
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let res = core.run(client.get(uri)).unwrap();
+        (res.status(), started.elapsed())
+    });
+
+    thread::sleep(Duration::from_millis(100)); // make sure slow request is in progress
+
+    // reactor is not blocked by the delayed response
+    let started = Instant::now();
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/fast"));
+    assert_eq!(status, StatusCode::Ok);
+    assert!(started.elapsed() < Duration::from_millis(300));
+
+    let (status, elapsed) = slow_request.join().unwrap();
+    assert_eq!(status, StatusCode::Ok);
+    assert!(elapsed >= Duration::from_millis(500));
+
+    let invalid = r#"{"request": {}, "response": {"status": 200}, "delay": {"type": "uniform", "min": 10, "max": 5}}"#;
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", invalid));
+    assert_eq!(status, StatusCode::BadRequest);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_script() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let exchange = r#"{
+        "request": {"method": "POST", "path": "/orders"},
+        "response": {"status": 200},
+        "script": "let order = request.body; response.status = 201; response.body = `created ${order}`;"
+    }"#;
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", exchange));
+    assert_eq!(status, StatusCode::Created);
+
+    let (status, _, body) = send(&mut core, json_request(&server, Method::Post, "/orders", "123"));
+    assert_eq!(status, StatusCode::Created);
+    assert_eq!(&body[..], b"created 123");
+
+    let invalid = r#"{"request": {}, "response": {"status": 200}, "script": "response.status = "}"#;
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", invalid));
+    assert_eq!(status, StatusCode::BadRequest);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_template() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let exchange = r#"{
+        "request": {"method": "POST", "path": "/orders/(\\d+)"},
+        "response": {
+            "status": 200,
+            "headers": [{"name": "X-Correlation-Id", "value": "{{header.X-Correlation-Id}}"}],
+            "body": "{\"order\": \"{{path.1}}\", \"customer\": {{body.$.customer.id}}}",
+            "template": true
+        }
+    }"#;
+    send(&mut core, json_request(&server, Method::Post, "/_control/responses", exchange));
+
+    let mut req = json_request(&server, Method::Post, "/orders/123", r#"{"customer": {"id": 7}}"#);
+    req.headers_mut().set_raw("X-Correlation-Id", "abc");
+    let (status, headers, body) = send(&mut core, req);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(headers.get_raw("X-Correlation-Id").unwrap(), "abc");
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["order"], "123");
+    assert_eq!(json["customer"], 7);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_response_sequence() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let exchange = r#"{
+        "request": {"path": "/flaky"},
+        "responses": [{"status": 500}, {"status": 503}, {"status": 200, "body": "ok"}],
+        "sequence": "stop"
+    }"#;
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", exchange));
+    assert_eq!(status, StatusCode::Created);
+
+    let statuses: Vec<StatusCode> = (0..4)
+        .map(|_| send(&mut core, request(&server, Method::Get, "/flaky")).0)
+        .collect();
+    assert_eq!(statuses,
+               vec![StatusCode::InternalServerError, StatusCode::ServiceUnavailable, StatusCode::Ok, StatusCode::NotFound]);
+
+    let invalid = r#"{"request": {}, "response": {"status": 200}, "responses": [{"status": 200}]}"#;
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", invalid));
+    assert_eq!(status, StatusCode::BadRequest);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_times() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let ok = r#"{"request": {"path": "/orders"}, "response": {"status": 200}}"#;
+    let once = r#"{"request": {"path": "/orders"}, "response": {"status": 500}, "times": 1}"#;
+    send(&mut core, json_request(&server, Method::Post, "/_control/responses", ok));
+    send(&mut core, json_request(&server, Method::Post, "/_control/responses", once));
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/orders"));
+    assert_eq!(status, StatusCode::InternalServerError);
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/orders"));
+    assert_eq!(status, StatusCode::Ok);
+
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/_control/responses"));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 1);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_scenarios() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let empty = r#"{"request": {"method": "GET", "path": "/cart"}, "response": {"status": 200, "body": "empty"},
+                    "scenario": "checkout", "required_state": "started"}"#;
+    let add = r#"{"request": {"method": "POST", "path": "/cart"}, "response": {"status": 201},
+                  "scenario": "checkout", "new_state": "has-items"}"#;
+    let full = r#"{"request": {"method": "GET", "path": "/cart"}, "response": {"status": 200, "body": "full"},
+                   "scenario": "checkout", "required_state": "has-items"}"#;
+    for exchange in &[empty, add, full] {
+        let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", exchange));
+        assert_eq!(status, StatusCode::Created);
+    }
+
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/cart"));
+    assert_eq!(&body[..], b"empty");
+    let (status, _, _) = send(&mut core, request(&server, Method::Post, "/cart"));
+    assert_eq!(status, StatusCode::Created);
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/cart"));
+    assert_eq!(&body[..], b"full");
+
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/_control/scenarios"));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json[0]["name"], "checkout");
+    assert_eq!(json[0]["state"], "has-items");
+
+    send(&mut core, request(&server, Method::Delete, "/_control/scenarios"));
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/cart"));
+    assert_eq!(&body[..], b"empty");
+
+    let state = r#"{"state": "has-items"}"#;
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Put, "/_control/scenarios/checkout", state));
+    assert_eq!(status, StatusCode::Ok);
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/cart"));
+    assert_eq!(&body[..], b"full");
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_priority() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let specific = r#"{"request": {"path": "/orders"}, "response": {"status": 200}}"#;
+    let catch_all = r#"{"request": {"path": ".*"}, "response": {"status": 503}, "priority": -10}"#;
+    send(&mut core, json_request(&server, Method::Post, "/_control/responses", specific));
+    send(&mut core, json_request(&server, Method::Post, "/_control/responses", catch_all)); // fixture reloaded later
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/orders"));
+    assert_eq!(status, StatusCode::Ok);
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/customers"));
+    assert_eq!(status, StatusCode::ServiceUnavailable);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_delete_matching_responses() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let orders = r#"{"request": {"path": "/orders/.*"}, "response": {"status": 200}}"#;
+    let order = r#"{"request": {"path": "/orders/1"}, "response": {"status": 200}}"#;
+    let customers = r#"{"request": {"path": "/customers"}, "response": {"status": 200}}"#;
+    for exchange in &[orders, order, customers] {
+        send(&mut core, json_request(&server, Method::Post, "/_control/responses", exchange));
+    }
+
+    let filter = r#"{"method": "GET", "path": "/orders/1"}"#;
+    let (status, _, body) = send(&mut core, json_request(&server, Method::Delete, "/_control/responses/delete", filter));
+    assert_eq!(status, StatusCode::Ok);
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["deleted"], 2);
+
+    let (_, _, body) = send(&mut core, json_request(&server, Method::Post, "/_control/responses/delete", filter));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["deleted"], 0);
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/customers"));
+    assert_eq!(status, StatusCode::Ok);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_faults() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    for fault in &["reset_connection", "close_without_response", "malformed_chunked_body", "random_garbage"] {
+        let path = format!("/{}", fault);
+        let exchange = format!(r#"{{"request": {{"path": "{}"}}, "response": {{"status": 200, "body": "ok", "fault": "{}"}}}}"#,
+                               path,
+                               fault);
+        let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", &exchange));
+        assert_eq!(status, StatusCode::Created);
+
+        assert!(try_send(&mut core, request(&server, Method::Get, &path)).is_err(), "Expected {} to fail", fault);
+    }
+
+    let drip = r#"{"request": {"path": "/drip"}, "response": {"status": 200, "body": "abcdef", "fault": {"drip": {"bytes": 2, "interval_ms": 100}}}}"#;
+    send(&mut core, json_request(&server, Method::Post, "/_control/responses", drip));
+    let started = Instant::now();
+    let (status, headers, body) = send(&mut core, request(&server, Method::Get, "/drip"));
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(headers.get::<ContentLength>(), Some(&ContentLength(6)));
+    assert_eq!(&body[..], b"abcdef");
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    // server still works after breaking connections
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/_control/version"));
+    assert_eq!(status, StatusCode::Ok);
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_streamed_response() {
+    before();
+
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let exchange = r#"{
+        "request": {"path": "/feed"},
+        "response": {
+            "status": 200,
+            "headers": [{"name": "Content-Type", "value": "application/x-ndjson"}],
+            "chunks": [
+                {"body": "{\"n\": 1}\n"},
+                {"body": "{\"n\": 2}\n", "delay": 200},
+                {"body": "{\"n\": 3}\n", "delay": 200}
+            ]
+        }
+    }"#;
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", exchange));
+    assert_eq!(status, StatusCode::Created);
+
+    let client = Client::new(&core.handle());
+    let started = Instant::now();
+    let work = client.request(request(&server, Method::Get, "/feed")).and_then(|res| {
+        assert_eq!(res.headers().get::<TransferEncoding>(), Some(&TransferEncoding::chunked()));
+        res.body().map(move |chunk| (started.elapsed(), chunk.to_vec())).collect()
+    });
+    let chunks = core.run(work).unwrap();
+
+    let body: Vec<u8> = chunks.iter().flat_map(|c| c.1.clone()).collect();
+    assert_eq!(&body[..], b"{\"n\": 1}\n{\"n\": 2}\n{\"n\": 3}\n");
+    assert!(chunks[0].0 < Duration::from_millis(200)); // first chunk not held back by later ones
+    assert!(chunks.last().unwrap().0 >= Duration::from_millis(400));
+
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_record_proxy() {
+    before();
+
+    let upstream = start_server();
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let exchange = r#"{"request": {"method": "GET", "path": "/orders/1"}, "response": {"status": 200, "body": "order 1"}}"#;
+    send(&mut core, json_request(&upstream, Method::Post, "/_control/responses", exchange));
+
+    let record_dir = std::env::temp_dir().join(format!("stubby-record-{}", server.local_addr().port()));
+    let proxy = serde_json::to_string(&json!({
+        "url": format!("http://{}", upstream.local_addr()),
+        "record": true,
+        "record_dir": record_dir
+    })).unwrap();
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Put, "/_control/proxy", &proxy));
+    assert_eq!(status, StatusCode::Ok);
+
+    let (status, _, body) = send(&mut core, request(&server, Method::Get, "/orders/1?expand=true"));
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(&body[..], b"order 1"); // query is ignored by upstream stub
+
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/_control/responses"));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json[0]["request"]["path"], "/orders/1");
+    assert_eq!(json[0]["request"]["params"][0]["value"], "true");
+    let id = json[0]["id"].as_str().unwrap().to_owned();
+    assert!(record_dir.join(format!("{}.json", id)).exists());
+
+    upstream.shutdown().expect("Clean server shutdown");
+    send(&mut core, request(&server, Method::Delete, "/_control/proxy"));
+
+    // replayed without upstream
+    let (status, _, body) = send(&mut core, request(&server, Method::Get, "/orders/1?expand=true"));
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(&body[..], b"order 1");
+
+    // upstream gone
+    let unreachable = r#"{"request": {"path": "/down"}, "proxy": {"url": "http://127.0.0.1:1"}}"#;
+    send(&mut core, json_request(&server, Method::Post, "/_control/responses", unreachable));
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/down"));
+    assert_eq!(status, StatusCode::BadGateway);
+
+    std::fs::remove_dir_all(&record_dir).unwrap();
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_stub_proxy() {
+    before();
+
+    let upstream = start_server();
+    let server = start_server();
+    let mut core = Core::new().unwrap();
+
+    let exchange = r#"{
+        "request": {"path": "/api/v2/orders/42", "headers": [{"name": "X-Api-Key", "value": "secret"}]},
+        "response": {"status": 200, "body": "order 42"}
+    }"#;
+    send(&mut core, json_request(&upstream, Method::Post, "/_control/responses", exchange));
+
+    let passthrough = serde_json::to_string(&json!({
+        "request": {"path": "/orders/(\\d+)"},
+        "proxy": {
+            "url": format!("http://{}/api", upstream.local_addr()),
+            "path": "/v2/orders/{{path.1}}",
+            "headers": [{"name": "X-Api-Key", "value": "secret"}],
+            "status": 203
+        }
+    })).unwrap();
+    let (status, _, _) = send(&mut core, json_request(&server, Method::Post, "/_control/responses", &passthrough));
+    assert_eq!(status, StatusCode::Created);
+
+    let (status, _, body) = send(&mut core, request(&server, Method::Get, "/orders/42"));
+    assert_eq!(status, StatusCode::NonAuthoritativeInformation);
+    assert_eq!(&body[..], b"order 42");
+
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/customers"));
+    assert_eq!(status, StatusCode::NotFound); // only the one endpoint is forwarded
+
+    upstream.shutdown().expect("Clean server shutdown");
+    server.shutdown().expect("Clean server shutdown");
+}
+
+#[test]
+fn test_load_stub_files() {
+    before();
+
+    let dir = std::env::temp_dir().join("stubby-integration-files");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::File::create(dir.join("orders.yaml")).unwrap()
+        .write_all(b"- request: {path: /orders}\n  response: {status: 200, body: from yaml}\n").unwrap();
+
+    let options = ServerOptions { stub_dirs: vec![dir.clone()], watch_interval: None };
+    let server = Server::start_with(LISTEN_ADDR.parse().unwrap(), options).expect("Server started");
+    let mut core = Core::new().unwrap();
+
+    let (status, _, body) = send(&mut core, request(&server, Method::Get, "/orders"));
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(&body[..], b"from yaml");
+
+    server.shutdown().expect("Clean server shutdown");
+
+    let options = ServerOptions { stub_dirs: vec![dir.join("missing")], watch_interval: None };
+    assert!(Server::start_with(LISTEN_ADDR.parse().unwrap(), options).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_reload_stub_files() {
+    before();
+
+    let dir = std::env::temp_dir().join("stubby-integration-reload");
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |content: &str| {
+        std::fs::File::create(dir.join("orders.json")).unwrap().write_all(content.as_bytes()).unwrap();
+    };
+    write(r#"{"request": {"path": "/orders"}, "response": {"status": 200, "body": "v1"}}"#);
+
+    let options = ServerOptions { stub_dirs: vec![dir.clone()], watch_interval: Some(Duration::from_millis(50)) };
+    let server = Server::start_with(LISTEN_ADDR.parse().unwrap(), options).expect("Server started");
+    let mut core = Core::new().unwrap();
+
+    let posted = r#"{"request": {"path": "/customers"}, "response": {"status": 200}}"#;
+    send(&mut core, json_request(&server, Method::Post, "/_control/responses", posted));
+
+    write(r#"{"request": {"path": "/orders"}, "response": {"status": 200, "body": "version 2"}}"#);
+    thread::sleep(Duration::from_millis(300));
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/orders"));
+    assert_eq!(&body[..], b"version 2");
+
+    write("{");
+    thread::sleep(Duration::from_millis(300));
+    let (_, _, body) = send(&mut core, request(&server, Method::Get, "/_control/files"));
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert!(json[0]["path"].as_str().unwrap().ends_with("orders.json"));
+    assert_eq!(json[0]["exchanges"], 1);
+    assert!(json[0]["error"].is_string());
+
+    std::fs::remove_file(dir.join("orders.json")).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/orders"));
+    assert_eq!(status, StatusCode::NotFound);
+    let (status, _, _) = send(&mut core, request(&server, Method::Get, "/customers"));
+    assert_eq!(status, StatusCode::Ok); // posted through the API, so not touched
+
+    server.shutdown().expect("Clean server shutdown");
+    std::fs::remove_dir_all(&dir).unwrap();
+}